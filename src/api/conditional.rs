@@ -0,0 +1,104 @@
+use std::time::{Duration, SystemTime};
+
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+
+use crate::utils::sha256_hex;
+
+
+/// Strong validators of a response body.
+#[derive(Clone, Debug)]
+pub struct Validators {
+    etag: String,
+    last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    pub fn new(sha256: &str, last_modified: Option<SystemTime>) -> Self {
+        Self { etag: format!("\"{}\"", sha256), last_modified }
+    }
+
+    /// Evaluates `If-None-Match`, or `If-Modified-Since` when the former is absent.
+    pub fn is_not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(value) = headers.get(header::IF_NONE_MATCH) {
+            return value.to_str().is_ok_and(|tags| tags.split(',').any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.trim_start_matches("W/") == self.etag
+            }));
+        }
+
+        headers.get(header::IF_MODIFIED_SINCE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| httpdate::parse_http_date(value).ok())
+            .zip(self.last_modified_secs())
+            .is_some_and(|(since, modified)| modified <= since)
+    }
+
+    pub fn apply(&self, response: &mut Response, max_age: Duration) {
+        let headers = response.headers_mut();
+        if let Ok(etag) = HeaderValue::from_str(&self.etag) {
+            headers.insert(header::ETAG, etag);
+        }
+        if let Some(modified) = self.last_modified
+            && let Ok(date) = HeaderValue::from_str(&httpdate::fmt_http_date(modified))
+        {
+            headers.insert(header::LAST_MODIFIED, date);
+        }
+        headers.insert(header::CACHE_CONTROL, cache_control(max_age));
+    }
+
+    /// Answers with `304 Not Modified` if the client copy is fresh, otherwise with the built response.
+    pub fn respond(
+        &self,
+        headers: &HeaderMap,
+        max_age: Duration,
+        response: impl FnOnce() -> Response,
+    ) -> Response {
+        let mut response = match self.is_not_modified(headers) {
+            true => StatusCode::NOT_MODIFIED.into_response(),
+            false => response(),
+        };
+        self.apply(&mut response, max_age);
+        response
+    }
+
+    /// HTTP dates have a one second precision.
+    fn last_modified_secs(&self) -> Option<SystemTime> {
+        self.last_modified.map(|modified| {
+            httpdate::parse_http_date(&httpdate::fmt_http_date(modified)).unwrap_or(modified)
+        })
+    }
+}
+
+
+pub fn cache_control(max_age: Duration) -> HeaderValue {
+    match max_age.as_secs() {
+        0 => HeaderValue::from_static("no-cache"),
+        secs => HeaderValue::from_str(&format!("public, max-age={}", secs))
+            .unwrap_or(HeaderValue::from_static("no-cache")),
+    }
+}
+
+
+/// Serializes the data as JSON with validators and a `Cache-Control` header. The ETag hashes
+/// the body, `last_modified` is the date of the data it was built from when there is one.
+pub fn json_response<T: Serialize>(
+    headers: &HeaderMap,
+    data: &T,
+    last_modified: Option<SystemTime>,
+    max_age: Duration,
+) -> Response {
+    let body = match serde_json::to_vec(data) {
+        Ok(body) => body,
+        Err(err) => {
+            eprintln!("{}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to serialize response").into_response();
+        },
+    };
+
+    let validators = Validators::new(&sha256_hex(&body), last_modified);
+    validators.respond(headers, max_age, || {
+        ([(header::CONTENT_TYPE, "application/json")], body).into_response()
+    })
+}
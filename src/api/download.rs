@@ -1,93 +1,431 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use axum::body::Bytes;
-use axum::extract::Query;
-use axum::http::{header, StatusCode};
-use axum::response::IntoResponse;
-use serde::Deserialize;
+use axum::body::Body;
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-use crate::bundle::{create_bundle, VersionedModule};
+use crate::bundle::{create_bundle, BundleOptions, ConflictMode, Layout, CONFLICTS_PATH, FAILURES_PATH};
+use crate::bundle::archive::ArchiveError;
+use crate::bundle::compat::{check_compatibility, Incompatibility};
+use crate::bundle::fetch::{FetchError, IntegrityError, ModuleFailure};
+use crate::bundle::limits::{BundleLimits, LimitError};
+use crate::bundle::merge::{Conflict, MergeError};
+use crate::bundle::shade::{Shade, ShadeError};
+use crate::bundle::resolve::{resolve_modules, ResolvedModule};
+use crate::bundle::select::select_modules;
+use crate::config;
+use crate::upstream::Upstream;
+use super::conditional::{cache_control, Validators};
 use super::manifest::fetch_manifest;
+use super::versions::{fetch_versions, find_for_minecraft};
+
+
+pub const CONFLICTS_HEADER: &str = "x-bookshelf-conflicts";
+pub const INCOMPATIBILITIES_HEADER: &str = "x-bookshelf-incompatibilities";
+pub const FAILURES_HEADER: &str = "x-bookshelf-failures";
+/// Longest report sent in a header, the full report is always written to the bundle.
+const MAX_REPORT_LENGTH: usize = 4096;
 
 
 #[derive(Deserialize)]
 pub struct QueryParams {
-    version: String,
+    version: Option<String>,
+    minecraft: Option<String>,
     modules: String,
+    #[serde(default)]
+    weak_dependencies: bool,
+    #[serde(default)]
+    allow_incompatible: bool,
+    #[serde(default)]
+    best_effort: bool,
+    description: Option<String>,
+    #[serde(default)]
+    on_conflict: ConflictMode,
+    #[serde(default)]
+    layout: Layout,
+    shade: Option<String>,
+}
+
+/// A bundle described as JSON, equivalent to the query parameters of `GET /download`.
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub struct BundleRequest {
+    /// Bookshelf version used for modules without an explicit version.
+    #[schema(example = "2.2.2")]
+    version: Option<String>,
+    /// Minecraft version used to pick the newest compatible Bookshelf version instead.
+    #[schema(example = "1.21.4")]
+    minecraft: Option<String>,
+    modules: Vec<ModuleRequest>,
+    /// Also include weak dependencies.
+    #[serde(default)]
+    weak_dependencies: bool,
+    /// Bundle modules from versions that may not work together, reporting the issues as warnings.
+    #[serde(default)]
+    allow_incompatible: bool,
+    /// Bundle the modules that could be fetched, reporting the others, instead of failing.
+    #[serde(default)]
+    best_effort: bool,
+    /// Description of the generated pack.mcmeta.
+    description: Option<String>,
+    #[serde(default)]
+    on_conflict: ConflictMode,
+    #[serde(default)]
+    layout: Layout,
+    /// Moves the Bookshelf namespaces under `<shade>.`, to embed them privately in another pack.
+    #[schema(example = "mymap")]
+    shade: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub struct ModuleRequest {
+    /// A module id, a `tag:<tag>`, a glob pattern like `bs.*`, or any of them prefixed by `-` to exclude it.
+    #[schema(example = "bs.raycast")]
+    id: String,
+    /// Overrides the version of the bundle for this module.
+    version: Option<String>,
+}
+
+impl From<QueryParams> for BundleRequest {
+    fn from(params: QueryParams) -> Self {
+        Self {
+            modules: params.modules
+                .split(',')
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| match entry.rsplit_once(':') {
+                    // `tag:<tag>` is a selector, not a version override.
+                    Some(("tag" | "-tag", _)) | None => ModuleRequest { id: entry.to_string(), version: None },
+                    Some((id, version)) => ModuleRequest { id: id.to_string(), version: Some(version.to_string()) },
+                })
+                .collect(),
+            version: params.version,
+            minecraft: params.minecraft,
+            weak_dependencies: params.weak_dependencies,
+            allow_incompatible: params.allow_incompatible,
+            best_effort: params.best_effort,
+            description: params.description,
+            on_conflict: params.on_conflict,
+            layout: params.layout,
+            shade: params.shade,
+        }
+    }
+}
+
+impl BundleRequest {
+    fn options(&self) -> BundleOptions {
+        BundleOptions {
+            description: self.description.clone(),
+            on_conflict: self.on_conflict,
+            layout: self.layout,
+            shade: self.shade.clone(),
+            best_effort: self.best_effort,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ConflictReport {
+    message: String,
+    conflicts: Vec<Conflict>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct FetchReport {
+    message: String,
+    failures: Vec<ModuleFailure>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CompatibilityReport {
+    message: String,
+    incompatibilities: Vec<Incompatibility>,
+}
+
+/// Modules to bundle, along with the incompatibilities tolerated by `allow_incompatible`.
+pub struct Resolution {
+    pub modules: Vec<ResolvedModule>,
+    pub incompatibilities: Vec<Incompatibility>,
+}
+
+impl Resolution {
+    /// Reports tolerated incompatibilities in the `x-bookshelf-incompatibilities` header.
+    pub fn apply(&self, response: &mut Response) {
+        if self.incompatibilities.is_empty() {
+            return;
+        }
+        let report = self.incompatibilities.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(" ");
+        if let Ok(value) = HeaderValue::from_str(&report) {
+            response.headers_mut().insert(INCOMPATIBILITIES_HEADER, value);
+        }
+    }
 }
 
 #[utoipa::path(
     get,
     tag = "modules",
     summary = "Download modules",
-    description = "Create and download a bundled archive containing one or more specified modules.",
+    description = "Create and download a bundled archive containing one or more specified modules along with their dependencies.",
     path = "/download",
     params(
-        ("version" = String, Query, description = "Bookshelf version to use", example = "2.2.2"),
-        ("modules" = String, Query, description = "Comma-separated list of modules", example = "bs.block,bs.raycast")
+        ("version" = Option<String>, Query, description = "Bookshelf version to use", example = "2.2.2"),
+        ("minecraft" = Option<String>, Query, description = "Minecraft version to pick the newest compatible Bookshelf version for, instead of `version`", example = "1.21.4"),
+        ("modules" = String, Query, description = "Comma-separated list of modules, `tag:<tag>` selectors, glob patterns like `bs.*` and exclusions like `-bs.dump`, each optionally followed by `:<version>`", example = "bs.block,bs.raycast"),
+        ("weak_dependencies" = Option<bool>, Query, description = "Also include weak dependencies", example = false),
+        ("allow_incompatible" = Option<bool>, Query, description = "Bundle modules from versions that may not work together, reporting the issues in the `x-bookshelf-incompatibilities` header", example = false),
+        ("best_effort" = Option<bool>, Query, description = "Bundle the modules that could be fetched, reporting the others in the `x-bookshelf-failures` header and in `bookshelf.failures.json`", example = false),
+        ("description" = Option<String>, Query, description = "Description of the generated pack.mcmeta", example = "My Bookshelf bundle"),
+        ("on_conflict" = Option<ConflictMode>, Query, description = "Fail or only warn when modules ship different files at the same path", example = "fail"),
+        ("layout" = Option<Layout>, Query, description = "Merge modules, keep them separate or arrange them for a world directory", example = "merged"),
+        ("shade" = Option<String>, Query, description = "Move the Bookshelf namespaces under `<shade>.`, to embed them privately in another pack", example = "mymap"),
     ),
     responses(
         (status = 200, description = "Zip bundle created successfully", content_type = "application/zip"),
-        (status = 400, description = "Bad request, missing or invalid params"),
+        (status = 304, description = "Bundle did not change since the last request"),
+        (status = 400, description = "Bad request, missing or invalid params, unsupported Minecraft version, unknown or cyclic dependencies, incompatible modules or versions, or too many modules or versions", body = CompatibilityReport),
+        (status = 409, description = "Modules ship different files at the same path", body = ConflictReport),
+        (status = 413, description = "The module artifacts exceed the maximum bundle size"),
+        (status = 429, description = "Too many requests from this client, retry after the delay given by `Retry-After`"),
+        (status = 502, description = "Modules could not be fetched, or an upstream module archive does not match its published hash, exceeds the safety limits, contains unsafe paths or ships a file that cannot be merged", body = FetchReport),
+    )
+)]
+pub async fn download(
+    State(upstream): State<Upstream>,
+    Query(params): Query<QueryParams>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    download_bundle(&upstream, params.into(), headers).await
+}
+
+#[utoipa::path(
+    post,
+    tag = "modules",
+    summary = "Download modules from a JSON request",
+    description = "Same as `GET /download`, with the modules and bundling options given as a JSON body.",
+    path = "/download",
+    request_body = BundleRequest,
+    responses(
+        (status = 200, description = "Zip bundle created successfully", content_type = "application/zip"),
+        (status = 304, description = "Bundle did not change since the last request"),
+        (status = 400, description = "Bad request, missing or invalid params, unsupported Minecraft version, unknown or cyclic dependencies, incompatible modules or versions, or too many modules or versions", body = CompatibilityReport),
+        (status = 409, description = "Modules ship different files at the same path", body = ConflictReport),
+        (status = 413, description = "The module artifacts exceed the maximum bundle size"),
+        (status = 429, description = "Too many requests from this client, retry after the delay given by `Retry-After`"),
+        (status = 502, description = "Modules could not be fetched, or an upstream module archive does not match its published hash, exceeds the safety limits, contains unsafe paths or ships a file that cannot be merged", body = FetchReport),
     )
 )]
-pub async fn download(Query(params): Query<QueryParams>) -> impl IntoResponse {
-    if params.version.is_empty() || params.modules.is_empty() {
-        return (StatusCode::BAD_REQUEST, "Version and modules cannot be empty.").into_response();
+pub async fn download_json(
+    State(upstream): State<Upstream>,
+    headers: HeaderMap,
+    Json(request): Json<BundleRequest>,
+) -> impl IntoResponse {
+    download_bundle(&upstream, request, headers).await
+}
+
+
+async fn download_bundle(upstream: &Upstream, request: BundleRequest, headers: HeaderMap) -> Response {
+    let resolution = match resolve_request(upstream, &request).await {
+        Ok(resolution) => resolution,
+        Err(response) => return response,
+    };
+    let modules = resolution.modules.iter().map(|resolved| resolved.module.clone()).collect();
+
+    if let Some(prefix) = &request.shade
+        && let Err(err) = Shade::new(prefix)
+    {
+        return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+    }
+
+    let options = request.options();
+    // The version picked for a Minecraft version changes with every release, like `/minecraft/{mc_version}`.
+    let max_age = match request.minecraft {
+        Some(_) => config::get().cache.versions_ttl,
+        None => config::get().cache.manifest_ttl,
+    };
+
+    match create_bundle(upstream, modules, options).await {
+        Ok(bundle) => {
+            let validators = bundle.etag.map(|etag| Validators::new(&etag, bundle.modified));
+            if let Some(validators) = &validators
+                && validators.is_not_modified(&headers)
+            {
+                let mut response = StatusCode::NOT_MODIFIED.into_response();
+                validators.apply(&mut response, max_age);
+                resolution.apply(&mut response);
+                return response;
+            }
+
+            let headers = [
+                (header::CONTENT_TYPE, "application/zip"),
+                (header::CONTENT_DISPOSITION, "attachment; filename=\"bookshelf-packs.zip\""),
+            ];
+            let mut response = (StatusCode::OK, headers, Body::from_stream(bundle.stream)).into_response();
+            match validators {
+                Some(validators) => validators.apply(&mut response, max_age),
+                None => {
+                    response.headers_mut().insert(header::CACHE_CONTROL, cache_control(max_age));
+                },
+            }
+            // A partial bundle must not be reused once the missing modules can be fetched again.
+            if !bundle.failures.is_empty() {
+                response.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+            }
+            if let Some(value) = report_header(&bundle.conflicts, CONFLICTS_PATH) {
+                response.headers_mut().insert(CONFLICTS_HEADER, value);
+            }
+            if let Some(value) = report_header(&bundle.failures, FAILURES_PATH) {
+                response.headers_mut().insert(FAILURES_HEADER, value);
+            }
+            resolution.apply(&mut response);
+            response
+        }
+        Err(err) => match err.downcast_ref::<MergeError>() {
+            Some(MergeError::Conflicts(conflicts)) => (StatusCode::CONFLICT, Json(ConflictReport {
+                message: err.to_string(),
+                conflicts: conflicts.clone(),
+            })).into_response(),
+            Some(MergeError::IncompatibleFormats(_)) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+            Some(MergeError::InvalidFile { .. }) => {
+                eprintln!("{}", err);
+                (StatusCode::BAD_GATEWAY, err.to_string()).into_response()
+            },
+            None if let Some(FetchError(failures)) = err.downcast_ref::<FetchError>() => {
+                (StatusCode::BAD_GATEWAY, Json(FetchReport {
+                    message: err.to_string(),
+                    failures: failures.clone(),
+                })).into_response()
+            },
+            None if err.is::<LimitError>() => (StatusCode::PAYLOAD_TOO_LARGE, err.to_string()).into_response(),
+            None if err.is::<ShadeError>() => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+            None if err.is::<ArchiveError>() || err.is::<IntegrityError>() => {
+                eprintln!("{}", err);
+                (StatusCode::BAD_GATEWAY, err.to_string()).into_response()
+            },
+            None => {
+                eprintln!("{}", err);
+                (StatusCode::INTERNAL_SERVER_ERROR, "Failed to create the bundle.").into_response()
+            },
+        },
+    }
+}
+
+
+/// Fetches the manifests of every requested version, resolves the dependency closure
+/// and checks that the versions it spans can be bundled together.
+pub async fn resolve_request(upstream: &Upstream, request: &BundleRequest) -> Result<Resolution, Response> {
+    let version = match (&request.version, &request.minecraft) {
+        (Some(_), Some(_)) => return Err((
+            StatusCode::BAD_REQUEST,
+            "Version and minecraft cannot be used together.",
+        ).into_response()),
+        (Some(version), None) => version.to_string(),
+        (None, Some(minecraft)) => resolve_minecraft(upstream, minecraft).await?,
+        (None, None) => String::new(),
+    };
+
+    if version.is_empty() || request.modules.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "Version and modules cannot be empty.").into_response());
     }
 
-    let mut modules = vec![];
-    let mut versions: HashMap<&str, Vec<&str>> = HashMap::new();
+    let limits = BundleLimits::get();
+    let exceeded = |err: LimitError| (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+    limits.check_modules(request.modules.len()).map_err(exceeded)?;
+
+    let mut selection = vec![];
+    let mut manifests = HashMap::new();
 
-    for entry in params.modules.split(',') {
-        let (module_id, version) = entry.split_once(':').unwrap_or((entry, &params.version));
-        versions.entry(version).or_default().push(module_id);
+    for module in &request.modules {
+        let version = module.version.as_ref().unwrap_or(&version);
+        selection.push((module.id.to_string(), version.to_string()));
     }
 
-    for (version, module_ids) in versions {
-        let manifest = match fetch_manifest(version.to_string()).await {
-            Ok(Some(m)) => m.into_latest(),
-            Ok(None) => return (
+    let versions: HashSet<&String> = selection.iter().map(|(_, version)| version).collect();
+    limits.check_versions(versions.len()).map_err(exceeded)?;
+
+    for (_, version) in &selection {
+        if manifests.contains_key(version) {
+            continue;
+        }
+
+        match fetch_manifest(upstream, version.to_string()).await {
+            Ok(Some(m)) => manifests.insert(version.to_string(), m.into_latest()),
+            Ok(None) => return Err((
                 StatusCode::BAD_REQUEST,
                 format!("Version `{}` not found.", version),
-            ).into_response(),
+            ).into_response()),
             Err(err) => {
                 eprintln!("{}", err);
-                return (
+                return Err((
                     StatusCode::INTERNAL_SERVER_ERROR,
                     format!("Failed to retrieve manifest for version `{}`.", version),
-                ).into_response()
+                ).into_response())
             },
         };
-
-        for module_id in module_ids {
-            if let Some(module) = manifest.modules.iter().find(|m| m.id == module_id) {
-                modules.push(VersionedModule::new(
-                    module.id.clone(),
-                    module.slug.clone(),
-                    module.kind,
-                    version.to_string(),
-                ));
-            } else {
-                return (
-                    StatusCode::BAD_REQUEST,
-                    format!("Module `{}` does not exist in version `{}`.", module_id, version),
-                ).into_response();
-            }
-        }
     }
 
-    match create_bundle(modules).await {
-        Ok(data) => {
-            let headers = [
-                (header::CONTENT_TYPE, "application/zip"),
-                (header::CONTENT_DISPOSITION, "attachment; filename=\"bookshelf-packs.zip\""),
-            ];
-            (StatusCode::OK, headers, Bytes::from(data)).into_response()
-        }
-        Err(err) => {
+    let selection = select_modules(&manifests, &selection)
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()).into_response())?;
+
+    let modules = resolve_modules(&manifests, &selection.requested, &selection.excluded, request.weak_dependencies)
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()).into_response())?;
+    limits.check_modules(modules.len()).map_err(exceeded)?;
+
+    let minecraft_versions = fetch_versions(upstream)
+        .await
+        .map_err(|err| {
             eprintln!("{}", err);
-            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to create the bundle.").into_response()
-        },
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to fetch versions.").into_response()
+        })?
+        .into_iter()
+        .map(|version| (version.version, version.minecraft_versions))
+        .collect();
+    let incompatibilities = check_compatibility(
+        &selection.requested,
+        &modules,
+        &manifests,
+        &minecraft_versions,
+        request.weak_dependencies,
+    );
+
+    if !incompatibilities.is_empty() && !request.allow_incompatible {
+        return Err((StatusCode::BAD_REQUEST, Json(CompatibilityReport {
+            message: format!("{} incompatibilities found, use `allow_incompatible` to bundle anyway.", incompatibilities.len()),
+            incompatibilities,
+        })).into_response());
     }
+
+    Ok(Resolution { modules, incompatibilities })
+}
+
+
+/// Finds the newest Bookshelf version supporting a Minecraft version.
+/// Joins a report into a header value, keeping printable ASCII only and pointing
+/// to the `file` of the bundle when the report is too long.
+fn report_header(items: &[impl ToString], file: &str) -> Option<HeaderValue> {
+    if items.is_empty() {
+        return None;
+    }
+
+    let report = items.iter().map(|item| item.to_string()).collect::<Vec<_>>().join("; ");
+    let mut report: String = report.chars().filter(|c| c.is_ascii() && !c.is_ascii_control()).collect();
+    if report.len() > MAX_REPORT_LENGTH {
+        let suffix = format!("... see {}", file);
+        report.truncate(MAX_REPORT_LENGTH - suffix.len());
+        report.push_str(&suffix);
+    }
+    HeaderValue::from_str(&report).ok()
+}
+
+
+async fn resolve_minecraft(upstream: &Upstream, minecraft: &str) -> Result<String, Response> {
+    let versions = fetch_versions(upstream).await.map_err(|err| {
+        eprintln!("{}", err);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to fetch versions.").into_response()
+    })?;
+
+    find_for_minecraft(versions, minecraft)
+        .map(|version| version.version)
+        .map_err(|err| (StatusCode::BAD_REQUEST, Json(err)).into_response())
 }
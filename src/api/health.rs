@@ -0,0 +1,22 @@
+use axum::extract::State;
+use axum::http::{header, HeaderValue};
+use axum::response::IntoResponse;
+use axum::Json;
+
+use crate::upstream::health::SourceStatus;
+use crate::upstream::Upstream;
+
+
+#[utoipa::path(
+    get,
+    tag = "health",
+    summary = "Get source health",
+    description = "Get the recent error rate, latency and circuit breaker state of every source modules are downloaded from. Sources with an open circuit are skipped, which explains slower or failing downloads.",
+    path = "/health",
+    responses(
+        (status = 200, description = "Health of every source", body = [SourceStatus]),
+    )
+)]
+pub async fn health(State(upstream): State<Upstream>) -> impl IntoResponse {
+    ([(header::CACHE_CONTROL, HeaderValue::from_static("no-store"))], Json(upstream.health()))
+}
@@ -0,0 +1,127 @@
+use std::cmp::Reverse;
+use std::collections::HashSet;
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use serde::Serialize;
+use tokio::task;
+use utoipa::ToSchema;
+
+use crate::bundle::fetch::cached_artifact;
+use crate::bundle::inspect::{DetectedModule, Upload};
+use crate::bundle::{FetchedModule, VersionedModule};
+use crate::upstream::Upstream;
+use super::manifest::fetch_manifest;
+use super::versions::{fetch_versions, version_key};
+
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct Inspection {
+    modules: Vec<DetectedModule>,
+    /// Versions newer than the newest one detected, from newest to oldest.
+    newer_versions: Vec<String>,
+    upgrade: Option<Upgrade>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct Upgrade {
+    version: String,
+    /// Downloads the detected modules at the upgrade version.
+    #[schema(example = "/download?version=2.2.2&modules=bs.block,bs.raycast")]
+    download_url: String,
+    /// Detected modules that do not exist anymore in the upgrade version.
+    unavailable: Vec<String>,
+}
+
+#[utoipa::path(
+    post,
+    tag = "modules",
+    summary = "Inspect a bundle",
+    description = "Identify the modules and versions contained in a zip archive, such as a previously downloaded bundle or a zipped `datapacks` folder, and suggest an upgrade.",
+    path = "/inspect",
+    request_body(content = String, content_type = "application/zip", description = "Zip archive to inspect"),
+    responses(
+        (status = 200, description = "Modules found in the archive", body = Inspection),
+        (status = 400, description = "Invalid or unsafe zip archive"),
+        (status = 413, description = "Archive is too large"),
+        (status = 429, description = "Too many requests from this client, retry after the delay given by `Retry-After`"),
+    )
+)]
+pub async fn inspect(State(upstream): State<Upstream>, body: Bytes) -> impl IntoResponse {
+    let upload = match task::spawn_blocking(move || Upload::read(&body)).await {
+        Ok(Ok(upload)) => upload,
+        Ok(Err(err)) => return (StatusCode::BAD_REQUEST, format!("{:#}", err)).into_response(),
+        Err(err) => {
+            eprintln!("{}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to inspect the archive.").into_response();
+        },
+    };
+
+    let mut versions = match fetch_versions(&upstream).await {
+        Ok(versions) => versions,
+        Err(err) => {
+            eprintln!("{}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to fetch versions.").into_response();
+        },
+    };
+    versions.sort_by_key(|version| Reverse(version_key(&version.version)));
+
+    // Only modules whose namespace is present are compared, and only to artifacts already cached.
+    let namespaces = upload.namespaces();
+    let mut known = HashSet::new();
+    let mut candidates = vec![];
+    for version in &versions {
+        let Ok(Some(manifest)) = fetch_manifest(&upstream, version.version.clone()).await else {
+            continue;
+        };
+        for module in manifest.into_latest().modules.into_iter().filter(|m| namespaces.contains(&m.id)) {
+            known.insert(module.id.clone());
+            let module = VersionedModule::new(module.id, module.slug, module.kind, version.version.clone());
+            if let Some(artifact) = cached_artifact(&module).await {
+                candidates.push(FetchedModule { module, artifact });
+            }
+        }
+    }
+
+    let modules = task::spawn_blocking(move || upload.identify(&known, &candidates)).await;
+    let modules = match modules.map_err(anyhow::Error::from).and_then(|modules| modules) {
+        Ok(modules) => modules,
+        Err(err) => {
+            eprintln!("{}", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to inspect the archive.").into_response();
+        },
+    };
+
+    let newest = modules.iter().filter_map(|module| module.version.as_deref()).max_by_key(|v| version_key(v));
+    let newer_versions: Vec<String> = versions
+        .iter()
+        .filter(|version| newest.is_some_and(|newest| version_key(&version.version) > version_key(newest)))
+        .map(|version| version.version.clone())
+        .collect();
+
+    let outdated = !newer_versions.is_empty() || modules.iter().any(|module| module.version.is_none());
+    let upgrade = match versions.first() {
+        Some(latest) if outdated && !modules.is_empty() => upgrade_to(&upstream, &latest.version, &modules).await,
+        _ => None,
+    };
+
+    Json(Inspection { modules, newer_versions, upgrade }).into_response()
+}
+
+
+async fn upgrade_to(upstream: &Upstream, version: &str, modules: &[DetectedModule]) -> Option<Upgrade> {
+    let manifest = fetch_manifest(upstream, version.to_string()).await.ok()??.into_latest();
+    let (available, unavailable): (Vec<String>, Vec<String>) = modules
+        .iter()
+        .map(|module| module.id.clone())
+        .partition(|id| manifest.modules.iter().any(|m| &m.id == id));
+
+    (!available.is_empty()).then(|| Upgrade {
+        version: version.to_string(),
+        download_url: format!("/download?version={}&modules={}", version, available.join(",")),
+        unavailable,
+    })
+}
@@ -1,15 +1,15 @@
 use anyhow::{Context, Result};
-use axum::extract::Path;
-use axum::http::StatusCode;
-use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::IntoResponse;
 use cached::proc_macro::cached;
-use reqwest::Client;
-use tokio::time::Duration;
 
 use crate::manifest::ManifestKind;
 use crate::manifest::v2::Manifest;
-use crate::utils::{read_from_json_file, write_to_json_file};
+use crate::config;
+use crate::upstream::Upstream;
+use crate::utils::{modified, read_from_json_file, write_to_json_file};
+use super::conditional::json_response;
 use super::versions::{fetch_versions, Version};
 
 
@@ -24,12 +24,20 @@ use super::versions::{fetch_versions, Version};
     ),
     responses(
         (status = 200, description = "Manifest data for the specified version", body = Manifest),
+        (status = 304, description = "Manifest did not change since the last request"),
         (status = 404, description = "Manifest not found"),
     )
 )]
-pub async fn manifest(Path(version): Path<String>) -> impl IntoResponse {
-    match fetch_manifest(version.to_string()).await {
-        Ok(Some(data)) => Json(data.into_latest()).into_response(),
+pub async fn manifest(
+    State(upstream): State<Upstream>,
+    Path(version): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    match fetch_manifest(&upstream, version.to_string()).await {
+        Ok(Some(data)) => {
+            let modified = modified(&manifest_cache_path(&version)).await;
+            json_response(&headers, &data.into_latest(), modified, config::get().cache.manifest_ttl)
+        },
         Ok(None) => (StatusCode::NOT_FOUND).into_response(),
         Err(err) => {
             eprintln!("{}", err);
@@ -38,17 +46,24 @@ pub async fn manifest(Path(version): Path<String>) -> impl IntoResponse {
     }
 }
 
-#[cached(time = 86400, result = true, sync_writes = "by_key")]
-pub async fn fetch_manifest(version: String) -> Result<Option<ManifestKind>> {
-    let cache_path = format!("cache/{}/manifest.json", version);
+#[cached(
+    ty = "cached::TimedCache<String, Option<ManifestKind>>",
+    create = "{ cached::TimedCache::with_lifespan(config::get().cache.manifest_ttl) }",
+    result = true,
+    sync_writes = "by_key",
+    convert = r#"{ version.clone() }"#,
+    key = "String",
+)]
+pub async fn fetch_manifest(upstream: &Upstream, version: String) -> Result<Option<ManifestKind>> {
+    let cache_path = manifest_cache_path(&version);
     if let Ok(manifest) = read_from_json_file(&cache_path).await {
         return Ok(Some(manifest));
     }
 
-    let versions = fetch_versions().await.context("Failed to fetch versions")?;
+    let versions = fetch_versions(upstream).await.context("Failed to fetch versions")?;
 
     if let Some(version) = versions.into_iter().find(|entry| entry.version == version) {
-        let manifest = fetch_manifest_from_github(&version).await?;
+        let manifest = fetch_manifest_from_github(upstream, &version).await?;
         write_to_json_file(&cache_path, &manifest).await?;
         Ok(Some(manifest))
     } else {
@@ -56,9 +71,12 @@ pub async fn fetch_manifest(version: String) -> Result<Option<ManifestKind>> {
     }
 }
 
-async fn fetch_manifest_from_github(version: &Version) -> Result<ManifestKind> {
-    let client = Client::new();
-    let response = client.get(&version.manifest).send().await?.error_for_status()?;
+fn manifest_cache_path(version: &str) -> String {
+    config::get().cache.path(&format!("{}/manifest.json", version))
+}
+
+async fn fetch_manifest_from_github(upstream: &Upstream, version: &Version) -> Result<ManifestKind> {
+    let response = upstream.get(&version.manifest).await?;
     let manifest: ManifestKind = response.json().await?;
 
     Ok(manifest)
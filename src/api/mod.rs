@@ -1,3 +1,8 @@
+pub mod conditional;
 pub mod download;
+pub mod health;
+pub mod inspect;
 pub mod manifest;
+pub mod plan;
+pub mod rate_limit;
 pub mod versions;
@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use axum::extract::{Query, State};
+use axum::http::HeaderMap;
+use axum::response::IntoResponse;
+use futures::future::join_all;
+use serde::Serialize;
+use tokio::fs::try_exists;
+use utoipa::ToSchema;
+
+use crate::bundle::fetch::{locate_module, module_cache_path, ModuleSource};
+use crate::bundle::resolve::ResolvedModule;
+use crate::upstream::Upstream;
+use super::conditional::json_response;
+use super::download::{resolve_request, CompatibilityReport, QueryParams};
+
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct PlannedModule {
+    #[serde(flatten)]
+    pub resolved: ResolvedModule,
+    /// Where the artifact would be downloaded from, if any source provides it.
+    pub source: Option<ModuleSource>,
+    /// Whether the artifact is already available in the on-disk cache.
+    pub cached: bool,
+}
+
+#[utoipa::path(
+    get,
+    tag = "modules",
+    summary = "Plan a download",
+    description = "Explain what a download with the same parameters would contain without creating the bundle.",
+    path = "/download/plan",
+    params(
+        ("version" = Option<String>, Query, description = "Bookshelf version to use", example = "2.2.2"),
+        ("minecraft" = Option<String>, Query, description = "Minecraft version to pick the newest compatible Bookshelf version for, instead of `version`", example = "1.21.4"),
+        ("modules" = String, Query, description = "Comma-separated list of modules, `tag:<tag>` selectors, glob patterns like `bs.*` and exclusions like `-bs.dump`, each optionally followed by `:<version>`", example = "bs.block,bs.raycast"),
+        ("weak_dependencies" = Option<bool>, Query, description = "Also include weak dependencies", example = false),
+        ("allow_incompatible" = Option<bool>, Query, description = "Plan modules from versions that may not work together, reporting the issues in the `x-bookshelf-incompatibilities` header", example = false),
+    ),
+    responses(
+        (status = 200, description = "Modules that would be bundled", body = [PlannedModule]),
+        (status = 304, description = "Plan did not change since the last request"),
+        (status = 400, description = "Bad request, missing or invalid params, unsupported Minecraft version, unknown or cyclic dependencies, incompatible versions, or too many modules or versions", body = CompatibilityReport),
+        (status = 429, description = "Too many requests from this client, retry after the delay given by `Retry-After`"),
+    )
+)]
+pub async fn plan(
+    State(upstream): State<Upstream>,
+    Query(params): Query<QueryParams>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let resolution = match resolve_request(&upstream, &params.into()).await {
+        Ok(resolution) => resolution,
+        Err(response) => return response,
+    };
+
+    let planned = join_all(resolution.modules.iter().cloned().map(|resolved| {
+        let upstream = &upstream;
+        async move {
+            let source = locate_module(upstream, &resolved.module).await.ok();
+            let cached = try_exists(module_cache_path(&resolved.module)).await.unwrap_or(false);
+            PlannedModule { resolved, source, cached }
+        }
+    })).await;
+
+    // The plan depends on the state of the cache, clients must always revalidate it.
+    let mut response = json_response(&headers, &planned, None, Duration::ZERO);
+    resolution.apply(&mut response);
+    response
+}
@@ -0,0 +1,147 @@
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use axum::extract::{ConnectInfo, Request};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+static BUCKETS: OnceLock<DashMap<IpAddr, Bucket>> = OnceLock::new();
+
+/// The least recently seen quarter of the buckets is dropped once this many clients are tracked.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+
+/// Token bucket applied to every client, a request takes one token.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimit {
+    /// Requests a client can make in a row, 0 disables rate limiting.
+    pub burst: u32,
+    /// Tokens given back to every client each minute.
+    pub per_minute: u32,
+    /// Reverse proxies in front of the API, whose `X-Forwarded-For` entries are trusted.
+    pub trusted_proxies: usize,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self { burst: 30, per_minute: 60, trusted_proxies: 0 }
+    }
+}
+
+impl RateLimit {
+    /// Settings of the `rate_limit` section of the configuration.
+    pub fn get() -> &'static Self {
+        &config::get().rate_limit
+    }
+
+    /// The connecting peer, or the address the outermost trusted proxy received the request from.
+    /// IPv6 clients are identified by their /64 network, which a single host usually owns entirely.
+    fn client(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        match self.address(peer, headers).to_canonical() {
+            IpAddr::V6(address) => IpAddr::V6(Ipv6Addr::from_bits(address.to_bits() & !0 << 64)),
+            address => address,
+        }
+    }
+
+    fn address(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if self.trusted_proxies == 0 {
+            return peer;
+        }
+        let forwarded: Vec<&str> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        forwarded
+            .len()
+            .checked_sub(self.trusted_proxies)
+            .and_then(|index| forwarded[index].parse().ok())
+            .unwrap_or(peer)
+    }
+
+    fn refill_rate(&self) -> f64 {
+        self.per_minute as f64 / 60.0
+    }
+}
+
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, settings: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * settings.refill_rate()).min(settings.burst as f64);
+        self.updated = now;
+    }
+
+    /// Takes a token, or returns how many seconds to wait until one is available.
+    fn take(&mut self, settings: &RateLimit) -> Result<(), u64> {
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(((1.0 - self.tokens) / settings.refill_rate()).ceil().max(1.0) as u64)
+    }
+}
+
+
+/// Rejects requests with `429 Too Many Requests` and a `Retry-After` header
+/// once their client ran out of tokens.
+pub async fn rate_limit(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let settings = RateLimit::get();
+    if settings.burst == 0 {
+        return next.run(request).await;
+    }
+
+    let client = settings.client(peer.ip(), request.headers());
+    let buckets = BUCKETS.get_or_init(DashMap::new);
+    let now = Instant::now();
+
+    if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(&client) {
+        evict_oldest(buckets);
+    }
+
+    let taken = {
+        let mut bucket = buckets.entry(client).or_insert_with(|| Bucket { tokens: settings.burst as f64, updated: now });
+        bucket.refill(settings, now);
+        bucket.take(settings)
+    };
+
+    match taken {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, HeaderValue::from(retry_after))],
+            format!("Too many requests, retry in {} seconds.", retry_after),
+        ).into_response(),
+    }
+}
+
+
+/// Drops the buckets of the clients seen least recently, in one pass
+/// so that it only happens once every `MAX_TRACKED_CLIENTS / 4` new clients.
+fn evict_oldest(buckets: &DashMap<IpAddr, Bucket>) {
+    let mut updated: Vec<Instant> = buckets.iter().map(|bucket| bucket.updated).collect();
+    if updated.is_empty() {
+        return;
+    }
+    let index = updated.len() / 4;
+    let (_, &mut cutoff, _) = updated.select_nth_unstable(index);
+    buckets.retain(|_, bucket| bucket.updated > cutoff);
+}
@@ -1,14 +1,18 @@
+use std::time::SystemTime;
+
 use anyhow::Result;
-use axum::http::StatusCode;
-use axum::Json;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::IntoResponse;
+use axum::Json;
 use cached::proc_macro::cached;
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use tokio::time::Duration;
 use utoipa::ToSchema;
 
-use crate::utils::{read_from_json_file, write_to_json_file};
+use crate::config;
+use crate::upstream::Upstream;
+use crate::utils::{modified, read_from_file, read_from_json_file, write_to_file};
+use super::conditional::json_response;
 
 
 #[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
@@ -18,6 +22,14 @@ pub struct Version {
     pub manifest: String,
 }
 
+/// Returned when no Bookshelf version supports the requested Minecraft version.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct UnsupportedMinecraft {
+    pub message: String,
+    /// Every Minecraft version supported by at least one Bookshelf version.
+    pub supported_minecraft_versions: Vec<String>,
+}
+
 #[utoipa::path(
     get,
     tag = "versions",
@@ -26,11 +38,12 @@ pub struct Version {
     path = "/versions",
     responses(
         (status = 200, description = "List of available versions", body = [Version]),
+        (status = 304, description = "Versions did not change since the last request"),
     )
 )]
-pub async fn versions() -> impl IntoResponse {
-    match fetch_versions().await {
-        Ok(data) => Json(data).into_response(),
+pub async fn versions(State(upstream): State<Upstream>, headers: HeaderMap) -> impl IntoResponse {
+    match fetch_versions(&upstream).await {
+        Ok(data) => json_response(&headers, &data, versions_modified().await, config::get().cache.versions_ttl),
         Err(err) => {
             eprintln!("{}", err);
             (StatusCode::INTERNAL_SERVER_ERROR, "Failed to fetch versions").into_response()
@@ -38,37 +51,109 @@ pub async fn versions() -> impl IntoResponse {
     }
 }
 
-#[cached(time = 600, result = true, sync_writes = "by_key")]
-pub async fn fetch_versions() -> Result<Vec<Version>> {
-    let cache_path = "cache/versions.json";
-    match fetch_versions_from_github().await {
-        Ok(versions) => {
-            write_to_json_file(cache_path, &versions).await?;
-            Ok(versions)
+#[utoipa::path(
+    get,
+    tag = "versions",
+    summary = "Find version by Minecraft version",
+    description = "Get the newest version compatible with a specific Minecraft version.",
+    path = "/minecraft/{mc_version}",
+    params(
+        ("mc_version" = String, Path, description = "Minecraft version to find a compatible version for", example = "1.21.4"),
+    ),
+    responses(
+        (status = 200, description = "Newest compatible version", body = Version),
+        (status = 304, description = "Version did not change since the last request"),
+        (status = 404, description = "No version supports this Minecraft version", body = UnsupportedMinecraft),
+    )
+)]
+pub async fn minecraft(
+    State(upstream): State<Upstream>,
+    Path(mc_version): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    match fetch_versions(&upstream).await {
+        Ok(data) => match find_for_minecraft(data, &mc_version) {
+            Ok(version) => json_response(&headers, &version, versions_modified().await, config::get().cache.versions_ttl),
+            Err(err) => (StatusCode::NOT_FOUND, Json(err)).into_response(),
+        },
+        Err(err) => {
+            eprintln!("{}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to fetch versions").into_response()
+        }
+    }
+}
+
+/// Picks the newest version listing `minecraft` among its supported Minecraft versions.
+pub fn find_for_minecraft(versions: Vec<Version>, minecraft: &str) -> Result<Version, UnsupportedMinecraft> {
+    let mut supported: Vec<String> = versions
+        .iter()
+        .flat_map(|version| version.minecraft_versions.iter().cloned())
+        .collect();
+
+    match versions
+        .into_iter()
+        .filter(|version| version.minecraft_versions.iter().any(|mc| mc == minecraft))
+        .max_by(|a, b| version_key(&a.version).cmp(&version_key(&b.version)))
+    {
+        Some(version) => Ok(version),
+        None => {
+            supported.sort_by_key(|mc| version_key(mc));
+            supported.dedup();
+            Err(UnsupportedMinecraft {
+                message: format!(
+                    "No version supports Minecraft `{}`. Supported Minecraft versions: {}.",
+                    minecraft,
+                    supported.join(", "),
+                ),
+                supported_minecraft_versions: supported,
+            })
         },
-        Err(_) => read_from_json_file(cache_path).await,
     }
 }
 
-async fn fetch_versions_from_github() -> Result<Vec<Version>> {
-    let urls = vec![
-        "https://raw.githubusercontent.com/mcbookshelf/bookshelf/refs/heads/master/data/versions.json",
-        "https://raw.githubusercontent.com/mcbookshelf/bookshelf/refs/heads/master/meta/versions.json",
-    ];
+/// Numeric components of a dotted version, so that `1.21.10` sorts after `1.21.9`.
+pub fn version_key(version: &str) -> Vec<u64> {
+    version
+        .split('.')
+        .map(|part| part.chars().take_while(char::is_ascii_digit).collect::<String>().parse().unwrap_or(0))
+        .collect()
+}
 
-    let client = Client::new();
+#[cached(
+    ty = "cached::TimedCache<(), Vec<Version>>",
+    create = "{ cached::TimedCache::with_lifespan(config::get().cache.versions_ttl) }",
+    result = true,
+    sync_writes = "by_key",
+    convert = r#"{}"#,
+    key = "()",
+)]
+pub async fn fetch_versions(upstream: &Upstream) -> Result<Vec<Version>> {
+    let cache_path = config::get().cache.path("versions.json");
+    match fetch_versions_from_github(upstream).await {
+        Ok(versions) => {
+            // The date of the file is the date of the versions, it only changes along with them.
+            let data = serde_json::to_vec(&versions)?;
+            if read_from_file(&cache_path).await.ok().as_ref() != Some(&data) {
+                write_to_file(&cache_path, &data).await?;
+            }
+            Ok(versions)
+        },
+        Err(_) => read_from_json_file(&cache_path).await,
+    }
+}
 
-    for url in urls {
-        let response = client.get(url).send().await;
+/// When the list of versions last changed.
+async fn versions_modified() -> Option<SystemTime> {
+    modified(&config::get().cache.path("versions.json")).await
+}
 
-        match response {
-            Ok(response) if response.status().is_success() => {
+async fn fetch_versions_from_github(upstream: &Upstream) -> Result<Vec<Version>> {
+    for url in &config::get().sources.versions_urls {
+        match upstream.get(url).await {
+            Ok(response) => {
                 let versions: Vec<Version> = response.json().await?;
                 return Ok(versions);
             }
-            Ok(response) => {
-                eprintln!("Failed to fetch from {}: HTTP {}", url, response.status());
-            }
             Err(err) => {
                 eprintln!("Error fetching from {}: {}", url, err);
             }
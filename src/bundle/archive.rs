@@ -0,0 +1,187 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, Write};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use zip::read::ZipFile;
+use zip::ZipArchive;
+
+use crate::bundle::FetchedModule;
+use crate::config;
+
+
+/// Bounds applied to every archive before its entries are read.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArchiveLimits {
+    pub max_entries: usize,
+    /// Sum of the uncompressed sizes of all entries, in bytes.
+    #[serde(rename = "max_size")]
+    pub max_total_size: u64,
+    /// Highest allowed ratio between the uncompressed and the compressed size of the archive.
+    pub max_ratio: u64,
+}
+
+impl Default for ArchiveLimits {
+    fn default() -> Self {
+        Self {
+            max_entries: 10_000,
+            max_total_size: 256 * 1024 * 1024,
+            max_ratio: 100,
+        }
+    }
+}
+
+impl ArchiveLimits {
+    /// Limits of the `archive` section of the configuration.
+    pub fn get() -> &'static Self {
+        &config::get().archive
+    }
+}
+
+
+/// Errors name the archive they come from, which is the module for artifacts.
+#[derive(Clone, Debug)]
+pub enum ArchiveError {
+    TooManyEntries { archive: String, entries: usize, max: usize },
+    TooLarge { archive: String, size: u64, max: u64 },
+    SuspiciousRatio { archive: String, ratio: u64, max: u64 },
+    InvalidPath { archive: String, path: String, reason: &'static str },
+    SizeMismatch { archive: String, path: String },
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::TooManyEntries { archive, entries, max } => write!(
+                f, "Archive `{}` has {} entries, the limit is {}.", archive, entries, max,
+            ),
+            ArchiveError::TooLarge { archive, size, max } => write!(
+                f, "Archive `{}` unpacks to {} bytes, the limit is {}.", archive, size, max,
+            ),
+            ArchiveError::SuspiciousRatio { archive, ratio, max } => write!(
+                f, "Archive `{}` has a compression ratio of {}, the limit is {}.", archive, ratio, max,
+            ),
+            ArchiveError::InvalidPath { archive, path, reason } => write!(
+                f, "Archive `{}` contains an invalid path `{}`: {}.", archive, path, reason,
+            ),
+            ArchiveError::SizeMismatch { archive, path } => write!(
+                f, "Entry `{}` of archive `{}` is larger than its declared size.", path, archive,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+
+/// Opens the artifact of a module after checking it with `check_archive`.
+pub fn open_archive(fetched: &FetchedModule) -> Result<ZipArchive<BufReader<File>>> {
+    let file = File::open(&fetched.artifact.path)?;
+    let compressed = file.metadata()?.len();
+    let mut archive = ZipArchive::new(BufReader::new(file))?;
+    check_archive(&fetched.module, &mut archive, compressed)?;
+    Ok(archive)
+}
+
+
+/// Checks the entry count, sizes and paths of an archive against the `ArchiveLimits`,
+/// only the central directory is read to do so.
+pub fn check_archive<R: Read + Seek>(
+    name: impl fmt::Display,
+    archive: &mut ZipArchive<R>,
+    compressed: u64,
+) -> Result<()> {
+    let limits = ArchiveLimits::get();
+    let name = name.to_string();
+
+    if archive.len() > limits.max_entries {
+        return Err(ArchiveError::TooManyEntries { archive: name, entries: archive.len(), max: limits.max_entries }.into());
+    }
+
+    let mut total: u64 = 0;
+    for i in 0..archive.len() {
+        let file = archive.by_index_raw(i)?;
+        check_path(file.name_raw()).map_err(|reason| ArchiveError::InvalidPath {
+            archive: name.clone(),
+            path: String::from_utf8_lossy(file.name_raw()).into_owned(),
+            reason,
+        })?;
+        total = total.saturating_add(file.size());
+    }
+
+    if total > limits.max_total_size {
+        return Err(ArchiveError::TooLarge { archive: name, size: total, max: limits.max_total_size }.into());
+    }
+    let ratio = total / compressed.max(1);
+    if ratio > limits.max_ratio {
+        return Err(ArchiveError::SuspiciousRatio { archive: name, ratio, max: limits.max_ratio }.into());
+    }
+
+    Ok(())
+}
+
+
+/// Copies an entry without trusting the decompressor to stop at the declared size.
+pub fn copy_entry<R: Read>(
+    name: impl fmt::Display,
+    file: &mut ZipFile<'_, R>,
+    writer: &mut impl Write,
+) -> Result<u64> {
+    let size = file.size();
+    let copied = io::copy(&mut file.take(size.saturating_add(1)), writer)?;
+    if copied > size {
+        return Err(ArchiveError::SizeMismatch { archive: name.to_string(), path: file.name().to_string() }.into());
+    }
+    Ok(copied)
+}
+
+
+/// Entry names must be relative UTF-8 paths that stay inside the archive.
+fn check_path(name: &[u8]) -> Result<(), &'static str> {
+    let name = std::str::from_utf8(name).map_err(|_| "not valid UTF-8")?;
+    if name.is_empty() || name.contains('\0') {
+        return Err("empty or containing a NUL character");
+    }
+    if name.contains('\\') {
+        return Err("backslashes are not allowed");
+    }
+    let drive = name.as_bytes().first().is_some_and(u8::is_ascii_alphabetic) && name[1..].starts_with(':');
+    if name.starts_with('/') || drive || name.split('/').next().is_some_and(|first| first.ends_with(':')) {
+        return Err("absolute paths are not allowed");
+    }
+    if name.split('/').any(|component| component == "..") {
+        return Err("parent directory components are not allowed");
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_paths_are_accepted() {
+        for name in ["pack.mcmeta", "data/bs.load/function/load.mcfunction", "data/", "a/./b", "a..b/c..", "data/a:b"] {
+            assert_eq!(check_path(name.as_bytes()), Ok(()), "{}", name);
+        }
+    }
+
+    #[test]
+    fn escaping_paths_are_rejected() {
+        for name in ["/etc/passwd", "C:", "C:/Windows", "c:boot.ini", "file:/x", "..", "../x", "a/../../x", "a/.."] {
+            assert!(check_path(name.as_bytes()).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn malformed_names_are_rejected() {
+        assert_eq!(check_path(b"a\\..\\x"), Err("backslashes are not allowed"));
+        assert_eq!(check_path(b"data\\a.json"), Err("backslashes are not allowed"));
+        assert_eq!(check_path(b""), Err("empty or containing a NUL character"));
+        assert_eq!(check_path(b"a\0b"), Err("empty or containing a NUL character"));
+        assert_eq!(check_path(b"\xff"), Err("not valid UTF-8"));
+    }
+}
@@ -0,0 +1,291 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::bundle::{BundleOptions, FetchedModule};
+use crate::bundle::merge::Conflict;
+use crate::config;
+
+const BUNDLE_CACHE_DIR: &str = "bundles";
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+static INDEX: OnceLock<DashMap<String, IndexEntry>> = OnceLock::new();
+
+
+/// Size and last use of a cached bundle, to evict the least recently used ones.
+struct IndexEntry {
+    size: u64,
+    used: SystemTime,
+}
+
+
+/// Identifies the exact artifact a bundle was built from.
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+struct Fingerprint {
+    module: String,
+    size: u64,
+    modified: u128,
+}
+
+impl Fingerprint {
+    fn new(fetched: &FetchedModule) -> Result<Self> {
+        let metadata = fs::metadata(&fetched.artifact.path).context("Failed to read artifact metadata")?;
+        Ok(Self {
+            module: fetched.module.to_string(),
+            size: metadata.len(),
+            modified: metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos(),
+        })
+    }
+}
+
+
+/// Metadata stored next to a cached bundle.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CachedBundle {
+    fingerprints: Vec<Fingerprint>,
+    /// Modification date of the zip the metadata was written for.
+    written: u128,
+    pub conflicts: Vec<Conflict>,
+    #[serde(skip)]
+    pub modified: Option<SystemTime>,
+}
+
+
+/// Finished bundles keyed by the sorted set of modules and the bundling options.
+/// An entry is only served while every artifact it was built from is unchanged,
+/// and the cache is kept under `bundles_max_size` by evicting the least recently used bundles.
+pub struct BundleCache {
+    key: String,
+    etag: String,
+    fingerprints: Vec<Fingerprint>,
+}
+
+impl BundleCache {
+    pub fn new(modules: &[&FetchedModule], options: &BundleOptions) -> Result<Self> {
+        let mut modules = modules.to_vec();
+        modules.sort_by(|a, b| (&a.module.id, &a.module.version).cmp(&(&b.module.id, &b.module.version)));
+        modules.dedup_by(|a, b| a.module.id == b.module.id && a.module.version == b.module.version);
+
+        // Bundles embed the version of the generator, and their content may change with it.
+        let mut hasher = Sha256::new();
+        hasher.update(env!("CARGO_PKG_VERSION"));
+        hasher.update([0]);
+        for fetched in &modules {
+            hasher.update(fetched.module.to_string());
+            hasher.update([0]);
+        }
+        hasher.update(serde_json::to_vec(options)?);
+        let key = format!("{:x}", hasher.finalize());
+
+        // Bundles are reproducible, the same options and artifact bytes give the same archive.
+        let mut hasher = Sha256::new();
+        hasher.update(&key);
+        for fetched in &modules {
+            hasher.update(&fetched.artifact.sha256);
+            hasher.update([0]);
+        }
+
+        Ok(Self {
+            key,
+            etag: format!("{:x}", hasher.finalize()),
+            fingerprints: modules.into_iter().map(Fingerprint::new).collect::<Result<_>>()?,
+        })
+    }
+
+    /// Strong validator of the bundle, known before it is built.
+    pub fn etag(&self) -> &str {
+        &self.etag
+    }
+
+    /// Returns the path and metadata of the cached bundle if it is still valid.
+    /// Entries built from artifacts that changed since are deleted.
+    pub fn lookup(&self) -> Option<(PathBuf, CachedBundle)> {
+        let metadata = fs::read(entry_path(&self.key, "json")).ok()?;
+        let path = entry_path(&self.key, "zip");
+        let file = fs::metadata(&path).ok();
+
+        let mut cached = match (serde_json::from_slice::<CachedBundle>(&metadata), &file) {
+            (Ok(cached), Some(_)) if cached.fingerprints == self.fingerprints => cached,
+            _ => {
+                remove_entry(&self.key);
+                return None;
+            },
+        };
+
+        // A different date means the zip is being replaced and its metadata is not written yet.
+        cached.modified = file.as_ref().and_then(|file| file.modified().ok());
+        let written = cached.modified.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+        if written.is_none_or(|written| written.as_nanos() != cached.written) {
+            return None;
+        }
+
+        let size = file.map_or(0, |file| file.len());
+        index().insert(self.key.clone(), IndexEntry { size, used: SystemTime::now() });
+        Some((path, cached))
+    }
+
+    /// Creates a temporary file that only replaces the cached bundle once committed.
+    pub fn writer(&self) -> Result<CacheWriter> {
+        fs::create_dir_all(config::get().cache.path(BUNDLE_CACHE_DIR)).context("Failed to create bundle cache directory")?;
+        index();
+        let counter = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let temp_path = entry_path(&self.key, &format!("{}.tmp", counter));
+
+        Ok(CacheWriter {
+            file: BufWriter::new(File::create(&temp_path)?),
+            temp_path,
+            key: self.key.clone(),
+            fingerprints: self.fingerprints.clone(),
+        })
+    }
+}
+
+
+/// Bundles customized with free text, a description or a shade prefix, are not cached
+/// so that arbitrary requests cannot fill the cache.
+pub fn is_cacheable(options: &BundleOptions) -> bool {
+    options.description.is_none() && options.shade.is_none() && config::get().cache.bundles_max_size > 0
+}
+
+
+fn entry_path(key: &str, extension: &str) -> PathBuf {
+    PathBuf::from(config::get().cache.path(&format!("{}/{}.{}", BUNDLE_CACHE_DIR, key, extension)))
+}
+
+
+/// Cached bundles, listed from the cache directory on first use. Temporary files
+/// left by an interrupted write are deleted then, before this process creates any.
+fn index() -> &'static DashMap<String, IndexEntry> {
+    INDEX.get_or_init(|| {
+        let index = DashMap::new();
+        let Ok(entries) = fs::read_dir(config::get().cache.path(BUNDLE_CACHE_DIR)) else {
+            return index;
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("tmp") => {
+                    let _ = fs::remove_file(&path);
+                },
+                Some("zip") => {
+                    let key = path.file_stem().and_then(|stem| stem.to_str()).map(str::to_string);
+                    if let (Some(key), Ok(metadata)) = (key, entry.metadata()) {
+                        let used = metadata.modified().unwrap_or(UNIX_EPOCH);
+                        index.insert(key, IndexEntry { size: metadata.len(), used });
+                    }
+                },
+                _ => {},
+            }
+        }
+        index
+    })
+}
+
+
+fn remove_entry(key: &str) {
+    index().remove(key);
+    let _ = fs::remove_file(entry_path(key, "zip"));
+    let _ = fs::remove_file(entry_path(key, "json"));
+}
+
+
+/// Deletes the least recently used bundles until the cache fits in `max_size`.
+fn evict(max_size: u64) {
+    let mut entries: Vec<(String, u64, SystemTime)> = index()
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.size, entry.used))
+        .collect();
+    let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+    entries.sort_by_key(|(_, _, used)| *used);
+
+    for (key, size, _) in entries {
+        if total <= max_size {
+            break;
+        }
+        eprintln!("Evicting cached bundle {}", key);
+        remove_entry(&key);
+        total -= size;
+    }
+}
+
+
+pub struct CacheWriter {
+    file: BufWriter<File>,
+    temp_path: PathBuf,
+    key: String,
+    fingerprints: Vec<Fingerprint>,
+}
+
+impl CacheWriter {
+    pub fn commit(mut self, conflicts: Vec<Conflict>) -> Result<()> {
+        self.file.flush()?;
+        // The zip replaces the previous one first, `lookup` then rejects the old metadata
+        // until the new one is renamed into place.
+        let path = entry_path(&self.key, "zip");
+        fs::rename(&self.temp_path, &path).context("Failed to store bundle in cache")?;
+        let file = fs::metadata(&path)?;
+        let metadata = CachedBundle {
+            fingerprints: std::mem::take(&mut self.fingerprints),
+            written: file.modified()?.duration_since(UNIX_EPOCH)?.as_nanos(),
+            conflicts,
+            modified: None,
+        };
+        let temp_metadata = self.temp_path.with_extension("json.tmp");
+        fs::write(&temp_metadata, serde_json::to_vec(&metadata)?)?;
+        fs::rename(&temp_metadata, entry_path(&self.key, "json")).context("Failed to store bundle metadata in cache")?;
+
+        index().insert(self.key.clone(), IndexEntry { size: file.len(), used: SystemTime::now() });
+        evict(config::get().cache.bundles_max_size);
+        Ok(())
+    }
+
+    pub fn discard(self) {
+        let _ = fs::remove_file(&self.temp_path);
+    }
+}
+
+impl Write for CacheWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+
+/// Writes to the client and to the cache. Failing to write to the cache
+/// does not abort the response, the bundle is simply not cached.
+pub struct TeeWriter<W: Write> {
+    pub writer: W,
+    pub cache: Option<CacheWriter>,
+}
+
+impl<W: Write> Write for TeeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        if let Some(cache) = &mut self.cache
+            && let Err(err) = cache.write_all(&buf[..written])
+        {
+            eprintln!("Failed to write bundle to cache: {}", err);
+            if let Some(cache) = self.cache.take() {
+                cache.discard();
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
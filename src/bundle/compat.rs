@@ -0,0 +1,104 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::bundle::resolve::ResolvedModule;
+use crate::manifest::v2::Manifest;
+
+
+/// A reason why modules taken from different versions may not work together.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Incompatibility {
+    /// The same module is requested at several versions, only the first one is bundled.
+    DuplicateModule { id: String, versions: Vec<String> },
+    /// A dependency is bundled at another version than the module depending on it.
+    DependencyMismatch { module: String, version: String, dependency: String, dependency_version: String },
+    /// The Minecraft versions supported by each bundled version do not overlap.
+    NoCommonMinecraftVersion { minecraft_versions: BTreeMap<String, Vec<String>> },
+}
+
+impl fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Incompatibility::DuplicateModule { id, versions } => write!(
+                f, "Module `{}` is requested at several versions: {}.", id, versions.join(", "),
+            ),
+            Incompatibility::DependencyMismatch { module, version, dependency, dependency_version } => write!(
+                f, "Module `{}@{}` depends on `{}`, which is bundled at version `{}`.",
+                module, version, dependency, dependency_version,
+            ),
+            Incompatibility::NoCommonMinecraftVersion { minecraft_versions } => write!(
+                f, "Versions {} do not share any Minecraft version.",
+                minecraft_versions.keys().map(|version| format!("`{}`", version)).collect::<Vec<_>>().join(", "),
+            ),
+        }
+    }
+}
+
+
+/// Checks that the requested and resolved modules can be bundled together:
+/// every id is requested once, dependencies come from the version of the module
+/// depending on them and all versions support a common Minecraft version, according
+/// to `minecraft_versions` which maps each version to the Minecraft versions it supports.
+pub fn check_compatibility(
+    requested: &[(String, String)],
+    resolved: &[ResolvedModule],
+    manifests: &HashMap<String, Manifest>,
+    minecraft_versions: &HashMap<String, Vec<String>>,
+    weak_dependencies: bool,
+) -> Vec<Incompatibility> {
+    let mut incompatibilities = vec![];
+
+    let mut requested_versions: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for (id, version) in requested {
+        requested_versions.entry(id).or_default().push(version.to_string());
+    }
+    for (id, versions) in requested_versions.into_iter().filter(|(_, versions)| versions.len() > 1) {
+        incompatibilities.push(Incompatibility::DuplicateModule { id: id.to_string(), versions });
+    }
+
+    let bundled: HashMap<&str, &str> = resolved
+        .iter()
+        .map(|resolved| (resolved.module.id.as_str(), resolved.module.version.as_str()))
+        .collect();
+    for resolved in resolved {
+        let (id, version) = (&resolved.module.id, &resolved.module.version);
+        let Some(module) = manifests.get(version).and_then(|manifest| manifest.modules.iter().find(|m| &m.id == id)) else {
+            continue;
+        };
+
+        let weak = module.weak_dependencies.iter().filter(|_| weak_dependencies);
+        for dependency in module.dependencies.iter().chain(weak) {
+            if let Some(dependency_version) = bundled.get(dependency.as_str())
+                && dependency_version != version
+            {
+                incompatibilities.push(Incompatibility::DependencyMismatch {
+                    module: id.to_string(),
+                    version: version.to_string(),
+                    dependency: dependency.to_string(),
+                    dependency_version: dependency_version.to_string(),
+                });
+            }
+        }
+    }
+
+    let used: BTreeSet<&str> = bundled.values().copied().collect();
+    if used.len() > 1 {
+        let minecraft_versions: BTreeMap<String, Vec<String>> = used
+            .iter()
+            .map(|used| (used.to_string(), minecraft_versions.get(*used).cloned().unwrap_or_default()))
+            .collect();
+
+        let mut common = minecraft_versions.values();
+        let first: BTreeSet<&String> = common.next().map(|first| first.iter().collect()).unwrap_or_default();
+        let shared = common.fold(first, |shared, other| shared.into_iter().filter(|mc| other.contains(mc)).collect());
+        if shared.is_empty() {
+            incompatibilities.push(Incompatibility::NoCommonMinecraftVersion { minecraft_versions });
+        }
+    }
+
+    incompatibilities
+}
@@ -1,23 +1,170 @@
 use std::sync::{Arc, OnceLock};
-use std::time::{Duration, Instant};
+use std::time::Instant;
+
+use std::fmt;
 
 use anyhow::{Context, Result};
 use cached::proc_macro::cached;
 use dashmap::DashMap;
-use reqwest::Client;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use tokio::fs::{remove_file, try_exists};
 use tokio::sync::Semaphore;
 use tokio::time::timeout;
+use utoipa::ToSchema;
 
+use crate::bundle::limits::SizeBudget;
 use crate::bundle::VersionedModule;
-use crate::utils::{read_from_file, write_to_file};
-
-const FETCH_MODULE_COOLDOWN: Duration = Duration::from_secs(600);
+use crate::config;
+use crate::upstream::{Source, Upstream};
+use crate::utils::{digest_file, read_from_json_file, sha256_file, sha256_hex, write_to_file, write_to_json_file};
 
 static FETCH_MODULE_LAST: OnceLock<DashMap<String, Instant>> = OnceLock::new();
 static SEMAPHORE: OnceLock<Arc<Semaphore>> = OnceLock::new();
 
 
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "source", content = "url", rename_all = "snake_case")]
+pub enum ModuleSource {
+    Modrinth(String),
+    GithubRelease(String),
+}
+
+impl ModuleSource {
+    pub fn url(&self) -> &str {
+        match self {
+            ModuleSource::Modrinth(url) | ModuleSource::GithubRelease(url) => url,
+        }
+    }
+
+    pub fn upstream(&self) -> Source {
+        match self {
+            ModuleSource::Modrinth(_) => Source::Modrinth,
+            ModuleSource::GithubRelease(_) => Source::Github,
+        }
+    }
+}
+
+/// A module artifact stored in the on-disk cache, along with the metadata
+/// recorded next to it when it was downloaded.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Artifact {
+    #[serde(skip)]
+    pub path: String,
+    /// Unknown in metadata recorded before artifacts were checked against their source,
+    /// such artifacts are verified again.
+    pub source: Option<ModuleSource>,
+    /// Digest of the bytes first written to the cache, checked on every read.
+    pub sha256: String,
+    /// Digest published by the source, verified when the artifact was downloaded.
+    #[serde(default)]
+    pub upstream: Option<UpstreamHash>,
+}
+
+/// A digest published alongside an artifact by its source, as a hex string.
+#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq)]
+#[serde(tag = "algorithm", content = "hash", rename_all = "snake_case")]
+pub enum UpstreamHash {
+    Sha512(String),
+    Sha256(String),
+    Sha1(String),
+}
+
+impl UpstreamHash {
+    fn verify(&self, module: &VersionedModule, bytes: &[u8]) -> Result<(), IntegrityError> {
+        let actual = match self {
+            UpstreamHash::Sha512(_) => format!("{:x}", Sha512::digest(bytes)),
+            UpstreamHash::Sha256(_) => format!("{:x}", Sha256::digest(bytes)),
+            UpstreamHash::Sha1(_) => format!("{:x}", Sha1::digest(bytes)),
+        };
+        self.check(module, actual)
+    }
+
+    /// Digest of a file with the same algorithm, computed without holding the file in memory.
+    async fn digest_file(&self, path: &str) -> Result<String> {
+        match self {
+            UpstreamHash::Sha512(_) => digest_file::<Sha512>(path).await,
+            UpstreamHash::Sha256(_) => digest_file::<Sha256>(path).await,
+            UpstreamHash::Sha1(_) => digest_file::<Sha1>(path).await,
+        }
+    }
+
+    fn check(&self, module: &VersionedModule, actual: String) -> Result<(), IntegrityError> {
+        let (UpstreamHash::Sha512(expected) | UpstreamHash::Sha256(expected) | UpstreamHash::Sha1(expected)) = self;
+        match expected.eq_ignore_ascii_case(&actual) {
+            true => Ok(()),
+            false => Err(IntegrityError { module: module.to_string(), expected: expected.to_string(), actual }),
+        }
+    }
+}
+
+/// The bytes of an artifact do not match the digest they were expected to have.
+#[derive(Clone, Debug)]
+pub struct IntegrityError {
+    module: String,
+    expected: String,
+    actual: String,
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Artifact of module `{}` does not match its expected hash `{}`, got `{}`.", self.module, self.expected, self.actual)
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+/// Context attached to errors raised while downloading from a located source.
+#[derive(Clone, Debug)]
+pub struct SourceFailed(pub ModuleSource);
+
+impl fmt::Display for SourceFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Failed to download `{}`", self.0.url())
+    }
+}
+
+/// A module whose artifact could not be fetched.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct ModuleFailure {
+    #[schema(example = "bs.block@2.2.2")]
+    pub module: String,
+    /// The source that failed, unknown when no source provides the module.
+    #[serde(flatten)]
+    pub source: Option<ModuleSource>,
+    pub error: String,
+}
+
+impl ModuleFailure {
+    pub fn new(module: &VersionedModule, err: &anyhow::Error) -> Self {
+        Self {
+            module: module.to_string(),
+            source: err.downcast_ref::<SourceFailed>().map(|failed| failed.0.clone()),
+            error: format!("{:#}", err),
+        }
+    }
+}
+
+impl fmt::Display for ModuleFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.module, self.error)
+    }
+}
+
+/// Modules that could not be fetched, which fails the bundle unless it is built on a best effort basis.
+#[derive(Clone, Debug)]
+pub struct FetchError(pub Vec<ModuleFailure>);
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let modules: Vec<&str> = self.0.iter().map(|failure| failure.module.as_str()).collect();
+        write!(f, "Failed to fetch {} module(s): {}.", modules.len(), modules.join(", "))
+    }
+}
+
+impl std::error::Error for FetchError {}
+
 #[derive(Clone, Debug, Deserialize)]
 struct ModrinthVersion {
     files: Vec<ModrinthFile>,
@@ -27,6 +174,15 @@ struct ModrinthVersion {
 struct ModrinthFile {
     url: String,
     primary: bool,
+    #[serde(default)]
+    hashes: ModrinthHashes,
+    size: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct ModrinthHashes {
+    sha512: Option<String>,
+    sha1: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -39,60 +195,210 @@ struct GithubAsset {
     name: String,
     #[serde(rename = "browser_download_url")]
     url: String,
+    /// Formatted as `sha256:<hex>`, missing on assets uploaded before GitHub computed digests.
+    digest: Option<String>,
+    size: Option<u64>,
+}
+
+
+pub fn module_cache_path(module: &VersionedModule) -> String {
+    config::get().cache.path(&format!("{}/{}.zip", module.version, module.id))
 }
 
 
+/// Makes sure the artifact of a module is available in the on-disk cache
+/// and returns its location, so that it can be read without being held in memory.
+/// The size of the artifact is taken from the budget before it is downloaded.
 pub async fn fetch_module(
-    client: Client,
+    upstream: Upstream,
     module: VersionedModule,
-) -> Result<Vec<u8>> {
-    let cache_path = format!("cache/{}/{}.zip", module.version, module.id);
-    if let Ok(bytes) = read_from_file(&cache_path).await {
+    budget: &SizeBudget,
+) -> Result<Artifact> {
+    let cache_path = module_cache_path(&module);
+    let metadata_path = format!("{}.json", cache_path);
+
+    let artifact = if try_exists(&cache_path).await.unwrap_or(false) {
         let now = Instant::now();
         let map = FETCH_MODULE_LAST.get_or_init(DashMap::new);
 
-        if map.get(&cache_path).is_none_or(|last| now.duration_since(*last.value()) > FETCH_MODULE_COOLDOWN) {
-            let sem = SEMAPHORE.get_or_init(|| Arc::new(Semaphore::new(3))).clone();
+        let cache = &config::get().cache;
+        if map.get(&cache_path).is_none_or(|last| now.duration_since(*last.value()) > cache.refresh_cooldown) {
+            let sem = SEMAPHORE.get_or_init(|| Arc::new(Semaphore::new(cache.refresh_concurrency))).clone();
             map.insert(cache_path.clone(), now);
 
+            let (upstream, module) = (upstream.clone(), module.clone());
             tokio::spawn(async move {
                 if let Ok(_permit) = sem.acquire().await {
-                    let url = match fetch_module_url_from_modrinth(&client, &module).await {
-                        Ok(url) => url,
+                    let url = match fetch_module_file_from_modrinth(&upstream, &module).await {
+                        Ok(file) => file.url,
                         Err(_) => return,
                     };
 
-                    if let Ok(Ok(resp)) = timeout(Duration::from_secs(5), client.get(url).send()).await {
-                        let _ = resp.bytes().await;
-                    }
+                    let _ = timeout(cache.refresh_timeout, upstream.get_bytes_from(Source::Modrinth, url)).await;
                 }
             });
         }
-        Ok(bytes)
+
+        read_cached_artifact(&upstream, &module, &cache_path, &metadata_path).await?
     } else {
-        let bytes = fetch_module_from_sources(&client, &module).await?;
-        write_to_file(&cache_path, &bytes).await?;
+        None
+    };
+
+    let artifact = match artifact {
+        Some(artifact) => {
+            budget.reserve(tokio::fs::metadata(&cache_path).await?.len())?;
+            artifact
+        },
+        None => {
+            let (source, hash, bytes) = fetch_module_from_sources(&upstream, &module, budget).await?;
+            // The metadata must exist once the artifact is visible, an artifact without
+            // metadata is checked against its source as one cached before metadata was recorded.
+            let artifact = Artifact { path: String::new(), source: Some(source), sha256: sha256_hex(&bytes), upstream: hash };
+            write_to_json_file(&metadata_path, &artifact).await?;
+            write_to_file(&cache_path, &bytes).await?;
+            artifact
+        },
+    };
+
+    Ok(Artifact { path: cache_path, ..artifact })
+}
 
-        Ok(bytes)
+
+/// Returns the artifact of a module only if it is already in the on-disk cache along with
+/// its metadata. The cache is left untouched, verifying and evicting artifacts is up to `fetch_module`.
+pub async fn cached_artifact(module: &VersionedModule) -> Option<Artifact> {
+    let cache_path = module_cache_path(module);
+    if !try_exists(&cache_path).await.unwrap_or(false) {
+        return None;
     }
+
+    let metadata_path = format!("{}.json", cache_path);
+    let artifact = read_from_json_file::<Artifact>(&metadata_path).await.ok()?;
+    Some(Artifact { path: cache_path, ..artifact })
 }
 
 
-async fn fetch_module_from_sources(
-    client: &Client,
+/// Reads the metadata of a cached artifact and checks that its bytes did not change
+/// since they were recorded. An artifact that no longer matches is evicted, so that
+/// it is downloaded again.
+async fn read_cached_artifact(
+    upstream: &Upstream,
     module: &VersionedModule,
-) -> Result<Vec<u8>> {
-    let url = match fetch_module_url_from_modrinth(client, module).await {
-        Ok(url) => url,
-        Err(_) => fetch_module_url_from_github(client, module)
-            .await
-            .context("Failed to fetch module from sources")?,
+    cache_path: &str,
+    metadata_path: &str,
+) -> Result<Option<Artifact>> {
+    let sha256 = sha256_file(cache_path).await?;
+
+    if let Ok(artifact) = read_from_json_file::<Artifact>(metadata_path).await {
+        if !artifact.sha256.eq_ignore_ascii_case(&sha256) {
+            eprintln!("{}", IntegrityError { module: module.to_string(), expected: artifact.sha256, actual: sha256 });
+            evict_artifact(cache_path, metadata_path).await?;
+            return Ok(None);
+        }
+        if artifact.source.is_some() {
+            return Ok(Some(artifact));
+        }
+    }
+
+    // Artifacts cached before their metadata was recorded were never checked
+    // against their source, they are verified once like a fresh download.
+    let Located { source, hash, .. } = locate_artifact(upstream, module).await?;
+    match &hash {
+        Some(hash) => {
+            if let Err(err) = hash.check(module, hash.digest_file(cache_path).await?) {
+                eprintln!("{}", err);
+                evict_artifact(cache_path, metadata_path).await?;
+                return Ok(None);
+            }
+        },
+        None => eprintln!("No hash published for module `{}`, it cannot be verified", module),
+    }
+
+    let artifact = Artifact { path: String::new(), source: Some(source), sha256, upstream: hash };
+    write_to_json_file(metadata_path, &artifact).await?;
+    Ok(Some(artifact))
+}
+
+
+async fn evict_artifact(cache_path: &str, metadata_path: &str) -> Result<()> {
+    remove_file(cache_path).await.context("Failed to evict artifact")?;
+    let _ = remove_file(metadata_path).await;
+    Ok(())
+}
+
+
+/// Finds where the artifact of a module can be downloaded from,
+/// preferring Modrinth and falling back to the GitHub release assets, sources
+/// whose circuit breaker is open are skipped without being requested.
+pub async fn locate_module(
+    upstream: &Upstream,
+    module: &VersionedModule,
+) -> Result<ModuleSource> {
+    Ok(locate_artifact(upstream, module).await?.source)
+}
+
+
+/// Where an artifact can be downloaded from, with the digest and size published by the source.
+struct Located {
+    source: ModuleSource,
+    hash: Option<UpstreamHash>,
+    size: Option<u64>,
+}
+
+
+/// Same as `locate_module`, along with the digest and size published by the source.
+async fn locate_artifact(
+    upstream: &Upstream,
+    module: &VersionedModule,
+) -> Result<Located> {
+    let modrinth = match fetch_module_file_from_modrinth(upstream, module).await {
+        Ok(file) => {
+            let hash = file.hashes.sha512.map(UpstreamHash::Sha512).or(file.hashes.sha1.map(UpstreamHash::Sha1));
+            return Ok(Located { source: ModuleSource::Modrinth(file.url), hash, size: file.size });
+        },
+        Err(err) => err,
     };
 
-    let response = client.get(url).send().await?.error_for_status()?;
-    let bytes = response.bytes().await?;
+    match fetch_module_asset_from_github(upstream, module).await {
+        Ok(asset) => {
+            let hash = asset.digest
+                .and_then(|digest| digest.strip_prefix("sha256:").map(str::to_string))
+                .map(UpstreamHash::Sha256);
+            Ok(Located { source: ModuleSource::GithubRelease(asset.url), hash, size: asset.size })
+        },
+        Err(github) => Err(anyhow::anyhow!(
+            "Failed to fetch module from sources (Modrinth: {:#}; GitHub release: {:#})", modrinth, github,
+        )),
+    }
+}
+
 
-    Ok(bytes.to_vec())
+/// Downloads an artifact and verifies it against the digest published by its source.
+/// The published size is checked against the budget first, the actual size when none is published.
+async fn fetch_module_from_sources(
+    upstream: &Upstream,
+    module: &VersionedModule,
+    budget: &SizeBudget,
+) -> Result<(ModuleSource, Option<UpstreamHash>, Vec<u8>)> {
+    let Located { source, hash, size } = locate_artifact(upstream, module).await?;
+    if let Some(size) = size {
+        budget.reserve(size)?;
+    }
+
+    let bytes = async {
+        let bytes = upstream.get_bytes_from(source.upstream(), source.url()).await?;
+
+        match &hash {
+            Some(hash) => hash.verify(module, &bytes)?,
+            None => eprintln!("No hash published for module `{}`, it cannot be verified", module),
+        }
+        Ok::<_, anyhow::Error>(bytes)
+    }.await.with_context(|| SourceFailed(source.clone()))?;
+
+    if size.is_none() {
+        budget.reserve(bytes.len() as u64)?;
+    }
+    Ok((source, hash, bytes.to_vec()))
 }
 
 
@@ -102,21 +408,18 @@ async fn fetch_module_from_sources(
     convert = r#"{ module.to_string() }"#,
     key = "String",
 )]
-async fn fetch_module_url_from_modrinth(
-    client: &Client,
+async fn fetch_module_file_from_modrinth(
+    upstream: &Upstream,
     module: &VersionedModule,
-) -> Result<String> {
-    let url = format!("https://api.modrinth.com/v3/project/{}/version/{}", module.slug, module.version);
-    let response = client.get(url).send().await?.error_for_status()?;
-    let data = response.json::<ModrinthVersion>().await?;
+) -> Result<ModrinthFile> {
+    let url = format!("{}/project/{}/version/{}", config::get().sources.modrinth_api, module.slug, module.version);
+    let body = upstream.get_bytes_from(Source::Modrinth, url).await?;
+    let data: ModrinthVersion = serde_json::from_slice(&body)?;
 
-    Ok(data.files
-        .iter()
-        .find(|file: &&ModrinthFile| file.primary)
-        .context("Failed to find file")?
-        .url
-        .to_string()
-    )
+    data.files
+        .into_iter()
+        .find(|file| file.primary)
+        .context("Failed to find file")
 }
 
 
@@ -126,18 +429,16 @@ async fn fetch_module_url_from_modrinth(
     convert = r#"{ module.to_string() }"#,
     key = "String",
 )]
-async fn fetch_module_url_from_github(
-    client: &Client,
+async fn fetch_module_asset_from_github(
+    upstream: &Upstream,
     module: &VersionedModule,
-) -> Result<String> {
-    let release = fetch_module_release_from_github(client, &module.version).await?;
+) -> Result<GithubAsset> {
+    let release = fetch_module_release_from_github(upstream, &module.version).await?;
 
-    Ok(release.assets
-        .iter()
+    release.assets
+        .into_iter()
         .find(|asset| asset.name.starts_with(&module.id))
-        .context("Failed to find asset")?
-        .url.to_string()
-    )
+        .context("Failed to find asset")
 }
 
 
@@ -148,11 +449,12 @@ async fn fetch_module_url_from_github(
     key = "String",
 )]
 async fn fetch_module_release_from_github(
-    client: &Client,
+    upstream: &Upstream,
     version: &str,
 ) -> Result<GithubRelease> {
-    let url = format!("https://api.github.com/repos/mcbookshelf/Bookshelf/releases/tags/v{}", version);
-    let response = client.get(url).header("User-Agent", "Bookshelf-API").send().await?.error_for_status()?;
+    let sources = &config::get().sources;
+    let url = format!("{}/repos/{}/releases/tags/v{}", sources.github_api, sources.github_repository, version);
+    let body = upstream.get_bytes_from(Source::Github, url).await?;
 
-    Ok(response.json().await?)
+    Ok(serde_json::from_slice(&body)?)
 }
@@ -0,0 +1,214 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::Cursor;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use zip::ZipArchive;
+
+use crate::bundle::archive::{check_archive, copy_entry, open_archive};
+use crate::bundle::metadata::METADATA_PATH;
+use crate::bundle::FetchedModule;
+use crate::utils::sha256_hex;
+
+/// Nested archives are only opened this deep, which covers bundles of packs.
+const MAX_DEPTH: usize = 2;
+
+
+/// How a module was recognized in an uploaded archive.
+#[derive(Copy, Clone, Debug, Serialize, Eq, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Detection {
+    /// Listed in the metadata of a bundle created by this API.
+    Metadata,
+    /// An untouched artifact of the module.
+    Artifact,
+    /// Every file of the module matches the artifact of this version.
+    Files,
+    /// Only some files of the module match, this version is the closest one.
+    Partial,
+    /// The namespace of the module is present but no known artifact matches it.
+    Namespace,
+}
+
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct DetectedModule {
+    pub id: String,
+    /// Unknown when no artifact available in the on-disk cache matches the module.
+    pub version: Option<String>,
+    pub detection: Detection,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct Metadata {
+    modules: Vec<MetadataModule>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MetadataModule {
+    id: String,
+    version: String,
+}
+
+
+/// Checksum and size of an entry, as recorded in the central directory.
+type Fingerprint = (u32, u64);
+
+
+/// What could be read from an uploaded archive and the packs nested in it.
+#[derive(Debug, Default)]
+pub struct Upload {
+    /// Entries of every pack, keyed by their path relative to the root of the pack.
+    packs: Vec<HashMap<String, Fingerprint>>,
+    /// Digests of the uploaded archive and of every nested archive.
+    archives: HashSet<String>,
+    /// Modules listed in the bundle metadata, if any.
+    listed: Vec<MetadataModule>,
+}
+
+impl Upload {
+    pub fn read(bytes: &[u8]) -> Result<Self> {
+        let mut upload = Self::default();
+        upload.read_archive("upload", bytes, 0)?;
+        Ok(upload)
+    }
+
+    /// Namespaces of the `data/` and `assets/` directories of every pack.
+    pub fn namespaces(&self) -> BTreeSet<String> {
+        self.packs
+            .iter()
+            .flat_map(|pack| pack.keys())
+            .filter_map(|path| match path.split('/').collect::<Vec<_>>().as_slice() {
+                ["data" | "assets", namespace, _, ..] => Some(namespace.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Identifies the modules of the upload. Modules listed in the bundle metadata are
+    /// trusted, others are compared to the `candidates` of their namespace, preferring
+    /// an identical artifact, then the version with the most identical files. Ties go
+    /// to the first candidate, so they are expected from the newest to the oldest.
+    pub fn identify(&self, known: &HashSet<String>, candidates: &[FetchedModule]) -> Result<Vec<DetectedModule>> {
+        let mut detected: Vec<DetectedModule> = self.listed.iter().map(|module| DetectedModule {
+            id: module.id.clone(),
+            version: Some(module.version.clone()),
+            detection: Detection::Metadata,
+        }).collect();
+
+        for namespace in self.namespaces() {
+            if !known.contains(&namespace) || detected.iter().any(|module| module.id == namespace) {
+                continue;
+            }
+
+            let candidates: Vec<&FetchedModule> = candidates.iter().filter(|c| c.module.id == namespace).collect();
+            if let Some(fetched) = candidates.iter().find(|c| self.archives.contains(&c.artifact.sha256)) {
+                detected.push(DetectedModule {
+                    id: namespace,
+                    version: Some(fetched.module.version.clone()),
+                    detection: Detection::Artifact,
+                });
+                continue;
+            }
+
+            let mut best: Option<(&FetchedModule, usize, usize)> = None;
+            for fetched in candidates {
+                let (matched, total) = self.compare(fetched)?;
+                if matched > 0 && best.is_none_or(|(_, m, t)| matched * t > m * total) {
+                    best = Some((fetched, matched, total));
+                }
+            }
+
+            detected.push(match best {
+                Some((fetched, matched, total)) => DetectedModule {
+                    id: namespace,
+                    version: Some(fetched.module.version.clone()),
+                    detection: if matched == total { Detection::Files } else { Detection::Partial },
+                },
+                None => DetectedModule { id: namespace, version: None, detection: Detection::Namespace },
+            });
+        }
+
+        detected.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(detected)
+    }
+
+    /// Counts the files of the module found identical in the closest pack of the upload.
+    fn compare(&self, fetched: &FetchedModule) -> Result<(usize, usize)> {
+        let id = &fetched.module.id;
+        let data = format!("data/{}/", id);
+        let assets = format!("assets/{}/", id);
+
+        let mut archive = open_archive(fetched)?;
+        let mut files = vec![];
+        for i in 0..archive.len() {
+            let file = archive.by_index_raw(i)?;
+            if !file.is_dir() && (file.name().starts_with(&data) || file.name().starts_with(&assets)) {
+                files.push((file.name().to_string(), (file.crc32(), file.size())));
+            }
+        }
+
+        let matched = self.packs
+            .iter()
+            .map(|pack| files.iter().filter(|(path, fingerprint)| pack.get(path) == Some(fingerprint)).count())
+            .max()
+            .unwrap_or(0);
+        Ok((matched, files.len()))
+    }
+
+    fn read_archive(&mut self, name: &str, bytes: &[u8], depth: usize) -> Result<()> {
+        self.archives.insert(sha256_hex(bytes));
+        let mut archive = ZipArchive::new(Cursor::new(bytes)).with_context(|| format!("`{}` is not a valid zip archive", name))?;
+        check_archive(name, &mut archive, bytes.len() as u64)?;
+
+        // Every directory holding a `pack.mcmeta` is the root of a pack, the archive root is one by default.
+        let mut roots: Vec<String> = archive
+            .file_names()
+            .filter_map(|path| path.strip_suffix("pack.mcmeta"))
+            .filter(|root| root.is_empty() || root.ends_with('/'))
+            .map(str::to_string)
+            .collect();
+        if !roots.iter().any(String::is_empty) {
+            roots.push(String::new());
+        }
+        roots.sort_by_key(|root| std::cmp::Reverse(root.len()));
+
+        let offset = self.packs.len();
+        self.packs.extend(roots.iter().map(|_| HashMap::new()));
+
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+            if file.is_dir() {
+                continue;
+            }
+
+            let path = file.name().to_string();
+            if path == METADATA_PATH {
+                let mut metadata = vec![];
+                copy_entry(name, &mut file, &mut metadata)?;
+                if let Ok(metadata) = serde_json::from_slice::<Metadata>(&metadata) {
+                    self.listed.extend(metadata.modules);
+                }
+                continue;
+            }
+
+            if path.ends_with(".zip") && depth < MAX_DEPTH {
+                let mut nested = vec![];
+                copy_entry(name, &mut file, &mut nested)?;
+                // Entries named like archives that are not zip files are simply ignored.
+                if ZipArchive::new(Cursor::new(nested.as_slice())).is_ok() {
+                    self.read_archive(&path, &nested, depth + 1)?;
+                }
+                continue;
+            }
+
+            if let Some((index, root)) = roots.iter().enumerate().find(|(_, root)| path.starts_with(root.as_str())) {
+                self.packs[offset + index].insert(path[root.len()..].to_string(), (file.crc32(), file.size()));
+            }
+        }
+
+        Ok(())
+    }
+}
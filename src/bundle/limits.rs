@@ -0,0 +1,101 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+
+/// Bounds on the work a single bundle request can trigger.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BundleLimits {
+    /// Modules bundled once selectors and dependencies are resolved.
+    pub max_modules: usize,
+    /// Distinct Bookshelf versions the modules are taken from.
+    pub max_versions: usize,
+    /// Sum of the sizes of the module artifacts, in bytes.
+    pub max_size: u64,
+    /// Modules fetched from upstream at the same time.
+    pub max_concurrent_fetches: usize,
+}
+
+impl Default for BundleLimits {
+    fn default() -> Self {
+        Self {
+            max_modules: 100,
+            max_versions: 4,
+            max_size: 64 * 1024 * 1024,
+            max_concurrent_fetches: 8,
+        }
+    }
+}
+
+impl BundleLimits {
+    /// Limits of the `bundle` section of the configuration.
+    pub fn get() -> &'static Self {
+        &config::get().bundle
+    }
+
+    pub fn check_modules(&self, modules: usize) -> Result<(), LimitError> {
+        match modules > self.max_modules {
+            true => Err(LimitError::TooManyModules { modules, max: self.max_modules }),
+            false => Ok(()),
+        }
+    }
+
+    pub fn check_versions(&self, versions: usize) -> Result<(), LimitError> {
+        match versions > self.max_versions {
+            true => Err(LimitError::TooManyVersions { versions, max: self.max_versions }),
+            false => Ok(()),
+        }
+    }
+
+    pub fn size_budget(&self) -> SizeBudget {
+        SizeBudget { used: AtomicU64::new(0), max: self.max_size }
+    }
+}
+
+
+/// Running total of the artifact sizes of a bundle, shared by concurrent fetches
+/// so that downloads stop as soon as the bundle cannot fit in the limit.
+pub struct SizeBudget {
+    used: AtomicU64,
+    max: u64,
+}
+
+impl SizeBudget {
+    pub fn reserve(&self, size: u64) -> Result<(), LimitError> {
+        let used = self.used.fetch_add(size, Ordering::Relaxed) + size;
+        match used > self.max {
+            true => Err(LimitError::SizeExceeded { size: used, max: self.max }),
+            false => Ok(()),
+        }
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub enum LimitError {
+    TooManyModules { modules: usize, max: usize },
+    TooManyVersions { versions: usize, max: usize },
+    SizeExceeded { size: u64, max: u64 },
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LimitError::TooManyModules { modules, max } => write!(
+                f, "The request covers {} modules, the limit is {}.", modules, max,
+            ),
+            LimitError::TooManyVersions { versions, max } => write!(
+                f, "The request spans {} versions, the limit is {}.", versions, max,
+            ),
+            LimitError::SizeExceeded { size, max } => write!(
+                f, "The modules weigh {} bytes, the limit is {}.", size, max,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
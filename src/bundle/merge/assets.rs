@@ -0,0 +1,125 @@
+use anyhow::Result;
+use serde_json::{Map, Value};
+
+use crate::bundle::VersionedModule;
+use super::{invalid_file, Strategy};
+
+
+/// Classifies the resource pack files that are designed to be combined:
+/// `assets/<namespace>/{lang,atlases,font}/*.json` and `assets/<namespace>/sounds.json`.
+pub fn asset_strategy(path: &str) -> Option<Strategy> {
+    let parts: Vec<&str> = path.split('/').collect();
+    match parts.as_slice() {
+        ["assets", namespace, "sounds.json"] if !namespace.is_empty() => Some(Strategy::Sounds),
+        ["assets", namespace, directory, file] if !namespace.is_empty() && file.ends_with(".json") => {
+            match *directory {
+                "lang" => Some(Strategy::Lang),
+                "atlases" => Some(Strategy::Atlas),
+                "font" => Some(Strategy::Font),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+
+/// Merges translations key by key, the first module defining a key wins.
+pub fn merge_lang(
+    path: &str,
+    contents: Vec<(&VersionedModule, Vec<u8>)>,
+) -> Result<Vec<u8>> {
+    let mut merged = Map::new();
+
+    for (module, bytes) in &contents {
+        for (key, value) in parse_object(path, module, bytes)? {
+            merged.entry(key).or_insert(value);
+        }
+    }
+
+    Ok(serde_json::to_vec_pretty(&Value::Object(merged))?)
+}
+
+
+/// Concatenates the array stored under `key` (`sources` for atlases, `providers`
+/// for fonts), skipping entries that are already present.
+pub fn merge_list(
+    path: &str,
+    key: &str,
+    contents: Vec<(&VersionedModule, Vec<u8>)>,
+) -> Result<Vec<u8>> {
+    let mut merged = Map::new();
+    let mut values: Vec<Value> = Vec::new();
+
+    for (module, bytes) in &contents {
+        let mut object = parse_object(path, module, bytes)?;
+        let entries = match object.remove(key) {
+            Some(Value::Array(entries)) => entries,
+            _ => return Err(invalid_file(path, module, format!("missing `{}` array", key)).into()),
+        };
+
+        for entry in entries {
+            if !values.contains(&entry) {
+                values.push(entry);
+            }
+        }
+        for (other, value) in object {
+            merged.entry(other).or_insert(value);
+        }
+    }
+
+    merged.insert(key.to_string(), Value::Array(values));
+    Ok(serde_json::to_vec_pretty(&Value::Object(merged))?)
+}
+
+
+/// Merges sound events: their `sounds` are concatenated, `replace` is set
+/// if any module asked for it and other fields are taken from the first module.
+pub fn merge_sounds(
+    path: &str,
+    contents: Vec<(&VersionedModule, Vec<u8>)>,
+) -> Result<Vec<u8>> {
+    let mut merged = Map::new();
+
+    for (module, bytes) in &contents {
+        for (event, definition) in parse_object(path, module, bytes)? {
+            let Value::Object(mut definition) = definition else {
+                return Err(invalid_file(path, module, format!("invalid sound event `{}`", event)).into());
+            };
+
+            let Some(Value::Object(existing)) = merged.get_mut(&event) else {
+                merged.insert(event, Value::Object(definition));
+                continue;
+            };
+
+            if let Some(Value::Array(sounds)) = definition.remove("sounds") {
+                let existing_sounds = existing
+                    .entry("sounds")
+                    .or_insert_with(|| Value::Array(Vec::new()));
+                if let Value::Array(existing_sounds) = existing_sounds {
+                    for sound in sounds {
+                        if !existing_sounds.contains(&sound) {
+                            existing_sounds.push(sound);
+                        }
+                    }
+                }
+            }
+            if let Some(Value::Bool(true)) = definition.remove("replace") {
+                existing.insert("replace".to_string(), Value::Bool(true));
+            }
+            for (key, value) in definition {
+                existing.entry(key).or_insert(value);
+            }
+        }
+    }
+
+    Ok(serde_json::to_vec_pretty(&Value::Object(merged))?)
+}
+
+
+fn parse_object(path: &str, module: &VersionedModule, bytes: &[u8]) -> Result<Map<String, Value>> {
+    match serde_json::from_slice(bytes).map_err(|err| invalid_file(path, module, err))? {
+        Value::Object(object) => Ok(object),
+        _ => Err(invalid_file(path, module, "expected a JSON object").into()),
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::bundle::{BundleOptions, FetchedModule, VersionedModule};
+use crate::bundle::archive::{copy_entry, open_archive};
+use crate::manifest::v2::ModuleKind;
+
+pub mod assets;
+pub mod pack;
+pub mod tags;
+
+
+#[derive(Clone, Debug)]
+pub enum MergeError {
+    IncompatibleFormats(Vec<String>),
+    InvalidFile { path: String, module: String, reason: String },
+    Conflicts(Vec<Conflict>),
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MergeError::IncompatibleFormats(formats) => write!(
+                f, "Modules do not share a common pack format: {}.", formats.join(", "),
+            ),
+            MergeError::InvalidFile { path, module, reason } => write!(
+                f, "Invalid `{}` in module `{}`: {}.", path, module, reason,
+            ),
+            MergeError::Conflicts(conflicts) => write!(
+                f, "{} file(s) are shipped with different contents by several modules.", conflicts.len(),
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+
+/// A path shipped with different contents by several modules.
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct Conflict {
+    pub path: String,
+    /// Modules shipping the path, the first one is the copy kept in the bundle.
+    pub modules: Vec<String>,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.path, self.modules.join(","))
+    }
+}
+
+
+/// Entries combined by a merge strategy and paths conflicting between modules.
+#[derive(Clone, Debug, Default)]
+pub struct Merged {
+    pub entries: BTreeMap<String, Vec<u8>>,
+    pub conflicts: Vec<Conflict>,
+}
+
+
+/// How the copies of a path shipped by several modules are combined.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Strategy {
+    PackMeta,
+    PackIcon,
+    Tag,
+    Lang,
+    Atlas,
+    Font,
+    Sounds,
+}
+
+impl Strategy {
+    pub fn for_path(path: &str, kind: ModuleKind) -> Option<Self> {
+        match (path, kind) {
+            ("pack.mcmeta", _) => Some(Strategy::PackMeta),
+            ("pack.png", _) => Some(Strategy::PackIcon),
+            (path, ModuleKind::DataPack) if tags::is_tag(path) => Some(Strategy::Tag),
+            (path, ModuleKind::ResourcePack) => assets::asset_strategy(path),
+            _ => None,
+        }
+    }
+}
+
+
+/// Reads every entry handled by a merge strategy and combines them, while
+/// other entries shipped by several modules are hashed to detect conflicts.
+/// This runs before the bundle is streamed so that invalid or incompatible
+/// modules are reported as a proper error response.
+pub fn merge_entries(
+    modules: &[FetchedModule],
+    options: &BundleOptions,
+) -> Result<Merged> {
+    let mut archives = modules.iter().map(open_archive).collect::<Result<Vec<_>>>()?;
+    let mut sources: BTreeMap<String, Vec<(&VersionedModule, Vec<u8>)>> = BTreeMap::new();
+    let mut copies: BTreeMap<String, Vec<(usize, usize)>> = BTreeMap::new();
+
+    for (index, (fetched, archive)) in modules.iter().zip(&mut archives).enumerate() {
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+            if file.is_dir() {
+                continue;
+            }
+
+            if Strategy::for_path(file.name(), fetched.module.kind).is_none() {
+                copies.entry(file.name().to_string()).or_default().push((index, i));
+                continue;
+            }
+
+            let mut bytes = Vec::with_capacity(file.size() as usize);
+            copy_entry(&fetched.module, &mut file, &mut bytes).context("Failed to read archive entry")?;
+            sources.entry(file.name().to_string()).or_default().push((&fetched.module, bytes));
+        }
+    }
+
+    // The CRC32 of the central directory is not trusted, an archive can declare any value.
+    let mut conflicts = Vec::new();
+    for (path, copies) in copies.into_iter().filter(|(_, copies)| copies.len() > 1) {
+        let digests = copies.iter().map(|&(index, i)| {
+            let mut hasher = Sha256::new();
+            copy_entry(&modules[index].module, &mut archives[index].by_index(i)?, &mut hasher)
+                .context("Failed to read archive entry")?;
+            Ok(hasher.finalize())
+        }).collect::<Result<Vec<_>>>()?;
+
+        if digests.iter().any(|digest| digest != &digests[0]) {
+            conflicts.push(Conflict {
+                path,
+                modules: copies.iter().map(|&(index, _)| modules[index].module.to_string()).collect(),
+            });
+        }
+    }
+
+    let mut entries = BTreeMap::new();
+    for (path, mut contents) in sources {
+        let Some(kind) = contents.first().map(|(module, _)| module.kind) else {
+            continue;
+        };
+
+        let bytes = match Strategy::for_path(&path, kind) {
+            // The bundle metadata is always rewritten, other files are kept as is when not shared.
+            Some(Strategy::PackMeta) => pack::merge_meta(&path, contents, modules, options)?,
+            Some(_) if contents.len() == 1 => contents.pop().map(|(_, bytes)| bytes).unwrap_or_default(),
+            Some(Strategy::PackIcon) => pack::choose_icon(contents),
+            Some(Strategy::Tag) => tags::merge_tag(&path, contents)?,
+            Some(Strategy::Lang) => assets::merge_lang(&path, contents)?,
+            Some(Strategy::Atlas) => assets::merge_list(&path, "sources", contents)?,
+            Some(Strategy::Font) => assets::merge_list(&path, "providers", contents)?,
+            Some(Strategy::Sounds) => assets::merge_sounds(&path, contents)?,
+            None => continue,
+        };
+        entries.insert(path, bytes);
+    }
+
+    Ok(Merged { entries, conflicts })
+}
+
+
+fn invalid_file(path: &str, module: &VersionedModule, reason: impl fmt::Display) -> MergeError {
+    MergeError::InvalidFile {
+        path: path.to_string(),
+        module: module.to_string(),
+        reason: reason.to_string(),
+    }
+}
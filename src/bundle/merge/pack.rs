@@ -0,0 +1,151 @@
+use anyhow::Result;
+use serde_json::{json, Map, Value};
+
+use crate::bundle::{BundleOptions, FetchedModule, VersionedModule};
+use super::{invalid_file, MergeError};
+
+
+/// Inclusive range of pack formats supported by a module.
+#[derive(Copy, Clone, Debug)]
+struct FormatRange {
+    min: i64,
+    max: i64,
+}
+
+impl FormatRange {
+    fn parse(pack: &Map<String, Value>) -> Option<Self> {
+        let pack_format = pack.get("pack_format").and_then(Value::as_i64);
+
+        // Since 1.21.9, `min_format` and `max_format` replace the older fields.
+        if let (Some(min), Some(max)) = (
+            pack.get("min_format").and_then(major_format),
+            pack.get("max_format").and_then(major_format),
+        ) {
+            return Some(Self { min, max });
+        }
+
+        match pack.get("supported_formats") {
+            Some(Value::Number(n)) => n.as_i64().map(|n| Self { min: n, max: n }),
+            Some(Value::Array(range)) => Some(Self {
+                min: range.first()?.as_i64()?,
+                max: range.get(1)?.as_i64()?,
+            }),
+            Some(Value::Object(range)) => Some(Self {
+                min: range.get("min_inclusive")?.as_i64()?,
+                max: range.get("max_inclusive")?.as_i64()?,
+            }),
+            _ => pack_format.map(|n| Self { min: n, max: n }),
+        }
+    }
+
+    fn intersect(self, other: Self) -> Option<Self> {
+        let range = Self { min: self.min.max(other.min), max: self.max.min(other.max) };
+        (range.min <= range.max).then_some(range)
+    }
+}
+
+fn major_format(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(n) => n.as_i64(),
+        Value::Array(parts) => parts.first()?.as_i64(),
+        _ => None,
+    }
+}
+
+
+/// Builds a `pack.mcmeta` accepted by every module of the bundle: the supported
+/// formats are intersected while `features`, `filter` and `overlays` are combined.
+pub fn merge_meta(
+    path: &str,
+    contents: Vec<(&VersionedModule, Vec<u8>)>,
+    modules: &[FetchedModule],
+    options: &BundleOptions,
+) -> Result<Vec<u8>> {
+    let mut range: Option<FormatRange> = None;
+    let mut ranges = Vec::with_capacity(contents.len());
+    let mut pack_format = None;
+    let mut uses_min_format = false;
+    let mut features = Vec::new();
+    let mut filters = Vec::new();
+    let mut overlays = Vec::new();
+    let mut incompatible = false;
+
+    for (module, bytes) in &contents {
+        let meta: Value = serde_json::from_slice(bytes).map_err(|err| invalid_file(path, module, err))?;
+        let pack = meta.get("pack")
+            .and_then(Value::as_object)
+            .ok_or_else(|| invalid_file(path, module, "missing `pack` object"))?;
+        let module_range = FormatRange::parse(pack)
+            .ok_or_else(|| invalid_file(path, module, "missing pack format"))?;
+
+        uses_min_format |= pack.contains_key("min_format");
+        pack_format = pack.get("pack_format").and_then(Value::as_i64).max(pack_format);
+        ranges.push(format!("{} ({}-{})", module, module_range.min, module_range.max));
+
+        match range.map(|range| range.intersect(module_range)) {
+            None => range = Some(module_range),
+            Some(Some(intersection)) => range = Some(intersection),
+            Some(None) => incompatible = true,
+        }
+
+        extend_unique(&mut features, meta.pointer("/features/enabled"));
+        extend_unique(&mut filters, meta.pointer("/filter/block"));
+        extend_unique(&mut overlays, meta.pointer("/overlays/entries"));
+    }
+
+    if incompatible {
+        return Err(MergeError::IncompatibleFormats(ranges).into());
+    }
+
+    let range = range.expect("pack.mcmeta is only merged when at least one module provides it");
+    let description = options.description.clone().unwrap_or_else(|| format!(
+        "Bookshelf: {}",
+        modules.iter().map(|m| m.module.to_string()).collect::<Vec<_>>().join(", "),
+    ));
+
+    let mut pack = Map::new();
+    pack.insert("pack_format".to_string(), json!(pack_format.unwrap_or(range.max).clamp(range.min, range.max)));
+    pack.insert("supported_formats".to_string(), json!({
+        "min_inclusive": range.min,
+        "max_inclusive": range.max,
+    }));
+    if uses_min_format {
+        pack.insert("min_format".to_string(), json!(range.min));
+        pack.insert("max_format".to_string(), json!(range.max));
+    }
+    pack.insert("description".to_string(), json!(description));
+
+    let mut meta = Map::new();
+    meta.insert("pack".to_string(), Value::Object(pack));
+    if !features.is_empty() {
+        meta.insert("features".to_string(), json!({ "enabled": features }));
+    }
+    if !filters.is_empty() {
+        meta.insert("filter".to_string(), json!({ "block": filters }));
+    }
+    if !overlays.is_empty() {
+        meta.insert("overlays".to_string(), json!({ "entries": overlays }));
+    }
+
+    Ok(serde_json::to_vec_pretty(&Value::Object(meta))?)
+}
+
+
+/// Keeps the icon of the module with the smallest id so that the result
+/// does not depend on the order in which modules were fetched.
+pub fn choose_icon(contents: Vec<(&VersionedModule, Vec<u8>)>) -> Vec<u8> {
+    contents
+        .into_iter()
+        .min_by(|(a, _), (b, _)| (&a.id, &a.version).cmp(&(&b.id, &b.version)))
+        .map(|(_, bytes)| bytes)
+        .unwrap_or_default()
+}
+
+
+fn extend_unique(values: &mut Vec<Value>, entries: Option<&Value>) {
+    for entry in entries.and_then(Value::as_array).into_iter().flatten() {
+        if !values.contains(entry) {
+            values.push(entry.clone());
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::bundle::VersionedModule;
+use super::invalid_file;
+
+
+/// Returns whether the path is a tag file, i.e. `data/<namespace>/tags/**/*.json`.
+pub fn is_tag(path: &str) -> bool {
+    let mut parts = path.split('/');
+    matches!(
+        (parts.next(), parts.next(), parts.next()),
+        (Some("data"), Some(namespace), Some("tags")) if !namespace.is_empty(),
+    ) && path.ends_with(".json")
+}
+
+
+/// Unions the `values` of every copy of a tag. An entry is only optional
+/// (`required: false`) when all modules declaring it mark it as optional,
+/// and the merged tag replaces lower packs if any module asked to.
+pub fn merge_tag(
+    path: &str,
+    contents: Vec<(&VersionedModule, Vec<u8>)>,
+) -> Result<Vec<u8>> {
+    let mut replace = false;
+    let mut values: Vec<(String, bool)> = Vec::new();
+
+    for (module, bytes) in &contents {
+        let tag: Value = serde_json::from_slice(bytes).map_err(|err| invalid_file(path, module, err))?;
+        replace |= tag.get("replace").and_then(Value::as_bool).unwrap_or(false);
+
+        let entries = tag.get("values")
+            .and_then(Value::as_array)
+            .ok_or_else(|| invalid_file(path, module, "missing `values` array"))?;
+
+        for entry in entries {
+            let (id, required) = match entry {
+                Value::String(id) => (id.as_str(), true),
+                Value::Object(entry) => (
+                    entry.get("id")
+                        .and_then(Value::as_str)
+                        .ok_or_else(|| invalid_file(path, module, "tag entry without `id`"))?,
+                    entry.get("required").and_then(Value::as_bool).unwrap_or(true),
+                ),
+                _ => return Err(invalid_file(path, module, "invalid tag entry").into()),
+            };
+
+            match values.iter_mut().find(|(value, _)| value == id) {
+                Some((_, value_required)) => *value_required |= required,
+                None => values.push((id.to_string(), required)),
+            }
+        }
+    }
+
+    let values: Vec<Value> = values.into_iter().map(|(id, required)| match required {
+        true => json!(id),
+        false => json!({ "id": id, "required": false }),
+    }).collect();
+
+    let tag = match replace {
+        true => json!({ "replace": true, "values": values }),
+        false => json!({ "values": values }),
+    };
+
+    Ok(serde_json::to_vec_pretty(&tag)?)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::bundle::merge::MergeError;
+    use crate::manifest::v2::ModuleKind;
+
+    use super::*;
+
+    const PATH: &str = "data/minecraft/tags/function/load.json";
+
+    fn module(id: &str) -> VersionedModule {
+        VersionedModule::new(id.to_string(), id.to_string(), ModuleKind::DataPack, "1.0".to_string())
+    }
+
+    fn merge(tags: &[(&VersionedModule, Value)]) -> Result<Value> {
+        let contents = tags.iter().map(|(module, tag)| (*module, serde_json::to_vec(tag).unwrap())).collect();
+        Ok(serde_json::from_slice(&merge_tag(PATH, contents)?)?)
+    }
+
+    #[test]
+    fn values_are_unioned_in_order() {
+        let (a, b) = (module("bs.a"), module("bs.b"));
+        let merged = merge(&[
+            (&a, json!({ "values": ["bs.a:load", "#bs.load:load"] })),
+            (&b, json!({ "values": ["bs.b:load", "#bs.load:load"] })),
+        ]).unwrap();
+
+        assert_eq!(merged, json!({ "values": ["bs.a:load", "#bs.load:load", "bs.b:load"] }));
+    }
+
+    #[test]
+    fn entries_stay_optional_only_when_optional_everywhere() {
+        let (a, b) = (module("bs.a"), module("bs.b"));
+        let merged = merge(&[
+            (&a, json!({ "values": [
+                { "id": "x:optional", "required": false },
+                { "id": "x:mixed", "required": false },
+                { "id": "x:implicit" },
+            ] })),
+            (&b, json!({ "values": [
+                { "id": "x:optional", "required": false },
+                "x:mixed",
+            ] })),
+        ]).unwrap();
+
+        assert_eq!(merged, json!({ "values": [
+            { "id": "x:optional", "required": false },
+            "x:mixed",
+            "x:implicit",
+        ] }));
+    }
+
+    #[test]
+    fn replace_is_kept_when_any_module_asks() {
+        let (a, b) = (module("bs.a"), module("bs.b"));
+        let merged = merge(&[
+            (&a, json!({ "replace": false, "values": ["x:a"] })),
+            (&b, json!({ "replace": true, "values": ["x:b"] })),
+        ]).unwrap();
+
+        assert_eq!(merged, json!({ "replace": true, "values": ["x:a", "x:b"] }));
+    }
+
+    #[test]
+    fn invalid_tags_name_the_module() {
+        let (a, b) = (module("bs.a"), module("bs.b"));
+        for tag in [json!({}), json!({ "values": [1] }), json!({ "values": [{ "required": true }] })] {
+            let err = merge(&[(&a, json!({ "values": [] })), (&b, tag)]).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<MergeError>(),
+                Some(MergeError::InvalidFile { path, module, .. }) if path == PATH && module == "bs.b@1.0",
+            ));
+        }
+
+        let err = merge_tag(PATH, vec![(&a, b"{".to_vec())]).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MergeError>(), Some(MergeError::InvalidFile { .. })));
+    }
+
+    #[test]
+    fn tag_paths() {
+        assert!(is_tag(PATH));
+        assert!(is_tag("data/bs.load/tags/block/nested/a.json"));
+        assert!(!is_tag("data/bs.load/function/a.json"));
+        assert!(!is_tag("data//tags/a.json"));
+        assert!(!is_tag("data/bs.load/tags/a.mcfunction"));
+    }
+}
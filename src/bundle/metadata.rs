@@ -0,0 +1,43 @@
+use serde::Serialize;
+
+use crate::bundle::{BundleOptions, FetchedModule, VersionedModule};
+use crate::bundle::fetch::ModuleSource;
+
+/// Path of the machine-readable description embedded in every bundle.
+pub const METADATA_PATH: &str = "bookshelf.bundle.json";
+
+
+/// Describes what a bundle contains and how it was created,
+/// so that it can later be audited or reproduced.
+#[derive(Clone, Debug, Serialize)]
+pub struct BundleMetadata<'a> {
+    generator: String,
+    options: &'a BundleOptions,
+    modules: Vec<BundledModule<'a>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct BundledModule<'a> {
+    #[serde(flatten)]
+    module: &'a VersionedModule,
+    #[serde(flatten)]
+    source: Option<&'a ModuleSource>,
+    sha256: &'a str,
+}
+
+impl<'a> BundleMetadata<'a> {
+    pub fn new(
+        modules: impl IntoIterator<Item = &'a FetchedModule>,
+        options: &'a BundleOptions,
+    ) -> Self {
+        Self {
+            generator: format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            options,
+            modules: modules.into_iter().map(|fetched| BundledModule {
+                module: &fetched.module,
+                source: fetched.artifact.source.as_ref(),
+                sha256: &fetched.artifact.sha256,
+            }).collect(),
+        }
+    }
+}
@@ -1,22 +1,45 @@
 use std::collections::HashSet;
 use std::fmt;
-use std::io::Cursor;
-use std::io::Write;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
 
 use anyhow::Result;
-use futures::stream::{FuturesUnordered, StreamExt};
-use reqwest::Client;
+use futures::stream::iter;
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::task;
+use utoipa::ToSchema;
 use zip::write::SimpleFileOptions;
-use zip::ZipArchive;
-use zip::ZipWriter;
+use zip::{CompressionMethod, DateTime, ZipWriter};
 
-use crate::bundle::fetch::fetch_module;
+use crate::bundle::archive::{copy_entry, open_archive};
+use crate::bundle::cache::{is_cacheable, BundleCache, CachedBundle, TeeWriter};
+use crate::bundle::fetch::{fetch_module, Artifact, FetchError, ModuleFailure};
+use crate::bundle::limits::{BundleLimits, LimitError, SizeBudget};
+use crate::bundle::merge::{merge_entries, Conflict, MergeError, Merged};
+use crate::bundle::metadata::{BundleMetadata, METADATA_PATH};
+use crate::bundle::shade::{is_text, Shade, ShadeError};
+use crate::bundle::stream::{BundleStream, ChannelWriter};
 use crate::manifest::v2::ModuleKind;
+use crate::upstream::Upstream;
 
+pub mod archive;
+pub mod cache;
+pub mod compat;
 pub mod fetch;
+pub mod inspect;
+pub mod limits;
+pub mod merge;
+pub mod metadata;
+pub mod resolve;
+pub mod select;
+pub mod shade;
+pub mod stream;
 
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, ToSchema)]
 pub struct VersionedModule {
     id: String,
     slug: String,
@@ -42,8 +65,78 @@ impl fmt::Display for VersionedModule {
 }
 
 
-pub async fn create_bundle(modules: Vec<VersionedModule>) -> Result<Vec<u8>> {
-    let client = Client::new();
+/// A module whose artifact is available in the on-disk cache.
+#[derive(Clone, Debug)]
+pub struct FetchedModule {
+    pub module: VersionedModule,
+    pub artifact: Artifact,
+}
+
+
+/// What to do when modules ship the same path with different contents.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictMode {
+    #[default]
+    Fail,
+    Warn,
+}
+
+
+/// How modules are arranged inside the bundle.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, Eq, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Layout {
+    /// A single merged pack, or `data_packs.zip` and `resource_packs.zip` when both kinds are present.
+    #[default]
+    Merged,
+    /// The untouched artifact of every module.
+    Separate,
+    /// A `datapacks/` folder and a `resources.zip`, ready to be extracted into a world or server directory.
+    World,
+}
+
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BundleOptions {
+    /// Overrides the description of the generated `pack.mcmeta`.
+    pub description: Option<String>,
+    pub on_conflict: ConflictMode,
+    pub layout: Layout,
+    /// Moves the Bookshelf namespaces under this prefix.
+    pub shade: Option<String>,
+    /// Bundles the modules that could be fetched instead of failing when some could not.
+    pub best_effort: bool,
+}
+
+
+pub struct Bundle {
+    pub stream: BundleStream,
+    /// Conflicts that were tolerated because of `ConflictMode::Warn`.
+    pub conflicts: Vec<Conflict>,
+    /// Identifies the content of complete bundles, whether they are built or served from the cache.
+    pub etag: Option<String>,
+    /// Date the archive was stored, only known when it is served from the bundle cache.
+    pub modified: Option<SystemTime>,
+    /// Modules left out because of `best_effort`.
+    pub failures: Vec<ModuleFailure>,
+}
+
+
+pub const CONFLICTS_PATH: &str = "bookshelf.conflicts.json";
+pub const FAILURES_PATH: &str = "bookshelf.failures.json";
+
+
+/// Fetches every module into the on-disk cache and merges the entries shared
+/// between modules, then streams the bundle from a blocking task while storing
+/// it in the bundle cache. Errors are reported before anything is sent.
+/// Partial bundles built on a best effort basis and customized bundles are never
+/// cached, and the artifacts must fit in the `BundleLimits` size.
+pub async fn create_bundle(
+    upstream: &Upstream,
+    modules: Vec<VersionedModule>,
+    options: BundleOptions,
+) -> Result<Bundle> {
     let mut data_packs = Vec::with_capacity(modules.len());
     let mut resource_packs = Vec::with_capacity(modules.len());
 
@@ -54,65 +147,329 @@ pub async fn create_bundle(modules: Vec<VersionedModule>) -> Result<Vec<u8>> {
         }
     }
 
-    if !data_packs.is_empty() && !resource_packs.is_empty() {
-        create_packs(&client, data_packs, resource_packs).await
-    } else if !data_packs.is_empty() {
-        create_pack(&client, data_packs).await
-    } else {
-        create_pack(&client, resource_packs).await
+    let budget = BundleLimits::get().size_budget();
+    let (data_packs, mut failures) = fetch_modules(upstream, data_packs, &budget).await?;
+    let (resource_packs, resource_failures) = fetch_modules(upstream, resource_packs, &budget).await?;
+    failures.extend(resource_failures);
+
+    let fetched_any = !data_packs.is_empty() || !resource_packs.is_empty();
+    let partial = options.best_effort && fetched_any;
+    if !failures.is_empty() && !partial {
+        return Err(FetchError(failures).into());
     }
+
+    let report = failures.clone();
+    let prepared = task::spawn_blocking(move || -> Result<_> {
+        let cache = match report.is_empty() {
+            true => Some(BundleCache::new(&data_packs.iter().chain(&resource_packs).collect::<Vec<_>>(), &options)?),
+            false => None,
+        };
+        let etag = cache.as_ref().map(|cache| cache.etag().to_string());
+        let cache = cache.filter(|_| is_cacheable(&options));
+        if let Some((path, cached)) = cache.as_ref().and_then(BundleCache::lookup) {
+            return Ok(Prepared::Cached { path, cached, etag });
+        }
+
+        let data_packs = Pack::new(data_packs, &options)?;
+        let resource_packs = Pack::new(resource_packs, &options)?;
+        let metadata = BundleMetadata::new(data_packs.modules.iter().chain(&resource_packs.modules), &options);
+
+        let mut extra = vec![(METADATA_PATH, serde_json::to_vec_pretty(&metadata)?)];
+        if !report.is_empty() {
+            extra.push((FAILURES_PATH, serde_json::to_vec_pretty(&report)?));
+        }
+
+        Ok(Prepared::Build { cache, etag, extra, layout: options.layout, data_packs, resource_packs })
+    }).await??;
+
+    let (writer, stream) = ChannelWriter::new();
+
+    let bundle = match prepared {
+        Prepared::Cached { path, cached, etag } => {
+            task::spawn_blocking(move || {
+                let mut writer = writer;
+                let result = File::open(path).and_then(|mut file| io::copy(&mut file, &mut writer));
+                if let Err(err) = result.and_then(|_| writer.flush()) {
+                    eprintln!("{}", err);
+                    writer.fail(err);
+                }
+            });
+            Bundle {
+                stream,
+                conflicts: cached.conflicts,
+                etag,
+                modified: cached.modified,
+                failures: vec![],
+            }
+        },
+        Prepared::Build { cache, etag, extra, layout, data_packs, resource_packs } => {
+            let conflicts: Vec<Conflict> = [&data_packs, &resource_packs]
+                .iter()
+                .flat_map(|pack| pack.merged.conflicts.iter().cloned())
+                .collect();
+            let report = conflicts.clone();
+
+            task::spawn_blocking(move || {
+                let cache = cache.and_then(|cache| cache.writer().inspect_err(|err| eprintln!("{}", err)).ok());
+                let mut writer = TeeWriter { writer, cache };
+
+                let extra: Vec<(&str, &[u8])> = extra.iter().map(|(name, bytes)| (*name, bytes.as_slice())).collect();
+                let result = write_bundle(&mut writer, layout, &data_packs, &resource_packs, &extra);
+
+                match result.and_then(|_| Ok(writer.flush()?)) {
+                    Ok(_) => {
+                        if let Some(cache) = writer.cache
+                            && let Err(err) = cache.commit(report)
+                        {
+                            eprintln!("{}", err);
+                        }
+                    },
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        if let Some(cache) = writer.cache {
+                            cache.discard();
+                        }
+                        writer.writer.fail(io::Error::other(err));
+                    },
+                }
+            });
+            Bundle { stream, conflicts, etag, modified: None, failures }
+        },
+    };
+
+    Ok(bundle)
 }
 
 
-async fn create_packs(
-    client: &Client,
-    data_packs: Vec<VersionedModule>,
-    resource_packs: Vec<VersionedModule>,
-) -> Result<Vec<u8>> {
-    let mut buffer = Vec::new();
-    let mut zip_writer = ZipWriter::new(Cursor::new(&mut buffer));
-    let options = SimpleFileOptions::default();
-    zip_writer.start_file("data_packs.zip", options)?;
-    zip_writer.write_all(&create_pack(client, data_packs).await?)?;
-    zip_writer.start_file("resource_packs.zip", options)?;
-    zip_writer.write_all(&create_pack(client, resource_packs).await?)?;
-    zip_writer.finish()?;
+enum Prepared {
+    /// A bundle built earlier from the same modules and options.
+    Cached { path: PathBuf, cached: CachedBundle, etag: Option<String> },
+    /// `cache` is missing for uncacheable bundles, `extra` entries are added at the root of the bundle.
+    Build {
+        cache: Option<BundleCache>,
+        etag: Option<String>,
+        extra: Vec<(&'static str, Vec<u8>)>,
+        layout: Layout,
+        data_packs: Pack,
+        resource_packs: Pack,
+    },
+}
+
+
+/// Modules bundled together along with the entries merged across them.
+struct Pack {
+    modules: Vec<FetchedModule>,
+    merged: Merged,
+    shade: Option<Shade>,
+}
+
+impl Pack {
+    fn new(mut modules: Vec<FetchedModule>, options: &BundleOptions) -> Result<Self> {
+        // The order of modules decides which copy wins, it must not depend on fetch order.
+        modules.sort_by(|a, b| (&a.module.id, &a.module.version).cmp(&(&b.module.id, &b.module.version)));
+        if options.layout == Layout::Separate {
+            if options.shade.is_some() {
+                return Err(ShadeError::UnsupportedLayout.into());
+            }
+            // Artifacts are copied untouched, but clients will unpack them.
+            for fetched in &modules {
+                open_archive(fetched)?;
+            }
+            return Ok(Self { modules, merged: Merged::default(), shade: None });
+        }
 
-    Ok(buffer)
+        let shade = options.shade.as_deref().map(Shade::new).transpose()?;
+        let merged = merge_entries(&modules, options)?;
+        if options.on_conflict == ConflictMode::Fail && !merged.conflicts.is_empty() {
+            return Err(MergeError::Conflicts(merged.conflicts).into());
+        }
+        Ok(Self { modules, merged, shade })
+    }
 }
 
 
-async fn create_pack(
-    client: &Client,
+/// Fetches every module, even when some of them fail, so that all failures are reported.
+/// At most `max_concurrent_fetches` modules are fetched at the same time, and fetching
+/// stops as soon as the artifacts exceed the size budget.
+async fn fetch_modules(
+    upstream: &Upstream,
     modules: Vec<VersionedModule>,
-) -> Result<Vec<u8>> {
-    let mut buffer = Vec::new();
-    let cursor = Cursor::new(&mut buffer);
+    budget: &SizeBudget,
+) -> Result<(Vec<FetchedModule>, Vec<ModuleFailure>)> {
+    let results: Vec<_> = iter(modules.into_iter().map(|module| async move {
+        match fetch_module(upstream.clone(), module.clone(), budget).await {
+            Ok(artifact) => Ok(Ok(FetchedModule { module, artifact })),
+            Err(err) if err.is::<LimitError>() => Err(err),
+            Err(err) => {
+                eprintln!("{:#}", err);
+                Ok(Err(ModuleFailure::new(&module, &err)))
+            },
+        }
+    })).buffered(BundleLimits::get().max_concurrent_fetches).try_collect().await?;
 
-    let options = SimpleFileOptions::default();
-    let mut writer = ZipWriter::new(cursor);
-    let mut seen = HashSet::new();
+    let mut fetched = Vec::with_capacity(results.len());
+    let mut failures = vec![];
+    for result in results {
+        match result {
+            Ok(module) => fetched.push(module),
+            Err(failure) => failures.push(failure),
+        }
+    }
+    Ok((fetched, failures))
+}
+
+
+/// Options shared by every entry so that identical requests produce identical archives.
+fn entry_options() -> SimpleFileOptions {
+    SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .compression_level(Some(6))
+        .last_modified_time(DateTime::default())
+        .unix_permissions(0o644)
+}
+
+
+fn write_bundle(
+    writer: impl Write,
+    layout: Layout,
+    data_packs: &Pack,
+    resource_packs: &Pack,
+    extra: &[(&str, &[u8])],
+) -> Result<()> {
+    let has_data_packs = !data_packs.modules.is_empty();
+    let has_resource_packs = !resource_packs.modules.is_empty();
+
+    match layout {
+        Layout::Merged if has_data_packs && has_resource_packs => create_packs(writer, &[
+            ("data_packs.zip", data_packs),
+            ("resource_packs.zip", resource_packs),
+        ], extra),
+        Layout::Merged if has_data_packs => create_pack(writer, data_packs, extra),
+        Layout::Merged => create_pack(writer, resource_packs, extra),
+        Layout::World => {
+            let packs = [("datapacks/bookshelf.zip", data_packs), ("resources.zip", resource_packs)];
+            let packs: Vec<_> = packs.into_iter().filter(|(_, pack)| !pack.modules.is_empty()).collect();
+            create_packs(writer, &packs, extra)
+        },
+        Layout::Separate => create_separate(writer, data_packs.modules.iter().chain(&resource_packs.modules), extra),
+    }
+}
+
+
+/// Writes each pack as a nested archive, `extra` entries are added at the root.
+fn create_packs(
+    writer: impl Write,
+    packs: &[(&str, &Pack)],
+    extra: &[(&str, &[u8])],
+) -> Result<()> {
+    let options = entry_options();
+    let mut zip_writer = ZipWriter::new_stream(writer);
+    for (name, bytes) in extra {
+        zip_writer.start_file(*name, options)?;
+        zip_writer.write_all(bytes)?;
+    }
+    for (name, pack) in packs {
+        zip_writer.start_file(*name, options)?;
+        create_pack(&mut zip_writer, pack, &[])?;
+    }
+    zip_writer.finish()?;
+
+    Ok(())
+}
 
-    let mut tasks = modules.into_iter().map(|module| {
-        let client = client.clone();
-        async move { fetch_module(client, module).await }
-    }).collect::<FuturesUnordered<_>>();
 
-    while let Some(result) = tasks.next().await {
-        let data = result?;
-        let mut archive = ZipArchive::new(Cursor::new(data))?;
+/// Writes the untouched artifact of every module, `extra` entries are added at the root.
+fn create_separate<'a>(
+    writer: impl Write,
+    modules: impl IntoIterator<Item = &'a FetchedModule>,
+    extra: &[(&str, &[u8])],
+) -> Result<()> {
+    let options = entry_options();
+    let mut zip_writer = ZipWriter::new_stream(writer);
+    for (name, bytes) in extra {
+        zip_writer.start_file(*name, options)?;
+        zip_writer.write_all(bytes)?;
+    }
+    for fetched in modules {
+        zip_writer.start_file(format!("{}-{}.zip", fetched.module.id, fetched.module.version), options)?;
+        io::copy(&mut File::open(&fetched.artifact.path)?, &mut zip_writer)?;
+    }
+    zip_writer.finish()?;
 
-        for i in 0..archive.len() {
-            let mut file = archive.by_index(i)?;
-            let name = file.name().to_string();
+    Ok(())
+}
+
+
+/// Writes a single pack, `extra` entries are added at its root.
+fn create_pack(
+    writer: impl Write,
+    pack: &Pack,
+    extra: &[(&str, &[u8])],
+) -> Result<()> {
+    let options = entry_options();
+    let mut writer = ZipWriter::new_stream(writer);
+    let mut seen = HashSet::new();
+
+    for (name, bytes) in extra {
+        seen.insert(name.to_string());
+        writer.start_file(*name, options)?;
+        writer.write_all(bytes)?;
+    }
 
-            if seen.insert(name.clone()) {
+    for (name, bytes) in &pack.merged.entries {
+        seen.insert(name.clone());
+        match &pack.shade {
+            Some(shade) => {
+                writer.start_file(shade.path(name), options)?;
+                writer.write_all(&shade.contents(name, bytes))?;
+            },
+            None => {
                 writer.start_file(name, options)?;
-                std::io::copy(&mut file, &mut writer)?;
+                writer.write_all(bytes)?;
+            },
+        }
+    }
+
+    if !pack.merged.conflicts.is_empty() {
+        seen.insert(CONFLICTS_PATH.to_string());
+        writer.start_file(CONFLICTS_PATH, options)?;
+        serde_json::to_writer_pretty(&mut writer, &pack.merged.conflicts)?;
+    }
+
+    for fetched in &pack.modules {
+        let mut archive = open_archive(fetched)?;
+        let mut names: Vec<String> = archive.file_names().map(str::to_string).collect();
+        names.sort();
+
+        for name in names {
+            let mut file = archive.by_name(&name)?;
+            if file.is_dir() {
+                continue;
+            }
+
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+
+            match &pack.shade {
+                Some(shade) if is_text(&name) => {
+                    let mut bytes = Vec::with_capacity(file.size() as usize);
+                    copy_entry(&fetched.module, &mut file, &mut bytes)?;
+                    writer.start_file(shade.path(&name), options)?;
+                    writer.write_all(&shade.contents(&name, &bytes))?;
+                },
+                Some(shade) => {
+                    writer.start_file(shade.path(&name), options)?;
+                    copy_entry(&fetched.module, &mut file, &mut writer)?;
+                },
+                None => {
+                    writer.start_file(name, options)?;
+                    copy_entry(&fetched.module, &mut file, &mut writer)?;
+                },
             }
         }
     }
 
     writer.finish()?;
-    Ok(buffer)
+    Ok(())
 }
@@ -0,0 +1,286 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::bundle::VersionedModule;
+use crate::manifest::v2::Manifest;
+
+
+#[derive(Clone, Debug)]
+pub enum ResolveError {
+    ModuleNotFound { id: String, version: String },
+    DependencyNotFound { id: String, dependent: String, version: String },
+    Cycle(Vec<String>),
+    Excluded { id: String, dependent: String },
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResolveError::ModuleNotFound { id, version } => write!(
+                f, "Module `{}` does not exist in version `{}`.", id, version,
+            ),
+            ResolveError::DependencyNotFound { id, dependent, version } => write!(
+                f, "Dependency `{}` of module `{}` does not exist in version `{}`.", id, dependent, version,
+            ),
+            ResolveError::Cycle(path) => write!(
+                f, "Cyclic dependency detected: {}.", path.join(" -> "),
+            ),
+            ResolveError::Excluded { id, dependent } => write!(
+                f, "Module `{}` is excluded but required by module `{}`.", id, dependent,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Inclusion {
+    /// The module was explicitly requested.
+    Requested,
+    /// The module was pulled in by the modules listed in `path`, from a requested one to its direct dependent.
+    Dependency { path: Vec<String> },
+    /// Same as `Dependency`, but the last link of the path is a weak dependency.
+    WeakDependency { path: Vec<String> },
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct ResolvedModule {
+    #[serde(flatten)]
+    pub module: VersionedModule,
+    pub reason: Inclusion,
+}
+
+
+/// Resolves the full dependency closure of the requested modules.
+/// A dependency that was explicitly requested keeps its requested version,
+/// otherwise it is taken from the same version as the module depending on it.
+/// A module requested at several versions is resolved at the first one.
+/// Excluded modules are left out, which fails if a module strictly depends on one.
+pub fn resolve_modules(
+    manifests: &HashMap<String, Manifest>,
+    requested: &[(String, String)],
+    excluded: &HashSet<String>,
+    weak_dependencies: bool,
+) -> Result<Vec<ResolvedModule>, ResolveError> {
+    let mut selected = HashMap::new();
+    for (id, version) in requested {
+        selected.entry(id.to_string()).or_insert_with(|| version.to_string());
+    }
+
+    let mut resolver = Resolver {
+        manifests,
+        selected,
+        excluded,
+        weak_dependencies,
+        path: Vec::new(),
+        done: HashSet::new(),
+        modules: Vec::with_capacity(requested.len()),
+    };
+
+    for (id, version) in requested {
+        if resolver.selected.get(id) == Some(version) {
+            resolver.visit(id, version, false)?;
+        }
+    }
+
+    Ok(resolver.modules)
+}
+
+
+struct Resolver<'a> {
+    manifests: &'a HashMap<String, Manifest>,
+    selected: HashMap<String, String>,
+    excluded: &'a HashSet<String>,
+    weak_dependencies: bool,
+    path: Vec<String>,
+    done: HashSet<String>,
+    modules: Vec<ResolvedModule>,
+}
+
+impl Resolver<'_> {
+    fn visit(&mut self, id: &str, version: &str, weak: bool) -> Result<(), ResolveError> {
+        if self.path.iter().any(|entry| entry == id) {
+            // Weak dependencies are only load-order hints, a cycle through them is harmless.
+            if weak {
+                return Ok(());
+            }
+            let mut cycle = self.path.clone();
+            cycle.push(id.to_string());
+            return Err(ResolveError::Cycle(cycle));
+        }
+
+        if self.done.contains(id) {
+            return Ok(());
+        }
+
+        if self.excluded.contains(id) {
+            return match (self.path.last(), weak) {
+                (Some(dependent), false) => Err(ResolveError::Excluded {
+                    id: id.to_string(),
+                    dependent: dependent.to_string(),
+                }),
+                _ => Ok(()),
+            };
+        }
+
+        let module = self.manifests
+            .get(version)
+            .and_then(|manifest| manifest.modules.iter().find(|m| m.id == id))
+            .ok_or_else(|| match self.path.last() {
+                Some(dependent) => ResolveError::DependencyNotFound {
+                    id: id.to_string(),
+                    dependent: dependent.to_string(),
+                    version: version.to_string(),
+                },
+                None => ResolveError::ModuleNotFound {
+                    id: id.to_string(),
+                    version: version.to_string(),
+                },
+            })?;
+
+        let mut dependencies: Vec<(&String, bool)> = module.dependencies.iter().map(|d| (d, false)).collect();
+        if self.weak_dependencies {
+            dependencies.extend(module.weak_dependencies.iter().map(|d| (d, true)));
+        }
+
+        self.path.push(id.to_string());
+        for (dependency, weak) in dependencies {
+            let dependency_version = self.selected
+                .get(dependency)
+                .cloned()
+                .unwrap_or_else(|| version.to_string());
+            self.visit(dependency, &dependency_version, weak)?;
+        }
+        self.path.pop();
+
+        let reason = match (self.selected.contains_key(id), weak) {
+            (true, _) => Inclusion::Requested,
+            (false, false) => Inclusion::Dependency { path: self.path.clone() },
+            (false, true) => Inclusion::WeakDependency { path: self.path.clone() },
+        };
+
+        self.done.insert(id.to_string());
+        self.modules.push(ResolvedModule {
+            module: VersionedModule::new(
+                module.id.clone(),
+                module.slug.clone(),
+                module.kind,
+                version.to_string(),
+            ),
+            reason,
+        });
+
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::manifest::v2::fixtures;
+    use super::*;
+
+    /// Modules given as `(id, dependencies, weak dependencies)`.
+    fn manifests(version: &str, modules: &[(&str, &[&str], &[&str])]) -> HashMap<String, Manifest> {
+        fixtures::manifests(version, modules.iter().map(|(id, dependencies, weak_dependencies)| (*id, json!({
+            "dependencies": dependencies,
+            "weak_dependencies": weak_dependencies,
+        }))))
+    }
+
+    fn requested(ids: &[&str]) -> Vec<(String, String)> {
+        ids.iter().map(|id| (id.to_string(), "1.0".to_string())).collect()
+    }
+
+    fn resolved(modules: &[ResolvedModule]) -> Vec<String> {
+        modules.iter().map(|resolved| resolved.module.to_string()).collect()
+    }
+
+    #[test]
+    fn dependencies_come_before_dependents() {
+        let manifests = manifests("1.0", &[("a", &["b"], &[]), ("b", &["c"], &[]), ("c", &[], &[])]);
+        let modules = resolve_modules(&manifests, &requested(&["a"]), &HashSet::new(), false).unwrap();
+
+        assert_eq!(resolved(&modules), ["c@1.0", "b@1.0", "a@1.0"]);
+        assert!(matches!(&modules[0].reason, Inclusion::Dependency { path } if path == &["a", "b"]));
+        assert!(matches!(modules[2].reason, Inclusion::Requested));
+    }
+
+    #[test]
+    fn strict_cycle_fails() {
+        let manifests = manifests("1.0", &[("a", &["b"], &[]), ("b", &["a"], &[])]);
+        let err = resolve_modules(&manifests, &requested(&["a"]), &HashSet::new(), false).unwrap_err();
+
+        assert!(matches!(err, ResolveError::Cycle(path) if path == ["a", "b", "a"]));
+    }
+
+    #[test]
+    fn weak_cycle_is_ignored() {
+        let manifests = manifests("1.0", &[("a", &["b"], &[]), ("b", &[], &["a"])]);
+        let modules = resolve_modules(&manifests, &requested(&["a"]), &HashSet::new(), true).unwrap();
+
+        assert_eq!(resolved(&modules), ["b@1.0", "a@1.0"]);
+    }
+
+    #[test]
+    fn weak_dependencies_are_opt_in() {
+        let manifests = manifests("1.0", &[("a", &[], &["b"]), ("b", &[], &[])]);
+
+        let modules = resolve_modules(&manifests, &requested(&["a"]), &HashSet::new(), false).unwrap();
+        assert_eq!(resolved(&modules), ["a@1.0"]);
+
+        let modules = resolve_modules(&manifests, &requested(&["a"]), &HashSet::new(), true).unwrap();
+        assert_eq!(resolved(&modules), ["b@1.0", "a@1.0"]);
+        assert!(matches!(modules[0].reason, Inclusion::WeakDependency { .. }));
+    }
+
+    #[test]
+    fn excluded_strict_dependency_fails() {
+        let manifests = manifests("1.0", &[("a", &["b"], &[]), ("b", &[], &[])]);
+        let excluded = HashSet::from(["b".to_string()]);
+        let err = resolve_modules(&manifests, &requested(&["a"]), &excluded, false).unwrap_err();
+
+        assert!(matches!(err, ResolveError::Excluded { id, dependent } if id == "b" && dependent == "a"));
+    }
+
+    #[test]
+    fn excluded_weak_dependency_is_left_out() {
+        let manifests = manifests("1.0", &[("a", &[], &["b"]), ("b", &[], &[])]);
+        let excluded = HashSet::from(["b".to_string()]);
+        let modules = resolve_modules(&manifests, &requested(&["a"]), &excluded, true).unwrap();
+
+        assert_eq!(resolved(&modules), ["a@1.0"]);
+    }
+
+    #[test]
+    fn missing_modules_are_reported() {
+        let manifests = manifests("1.0", &[("a", &["b"], &[])]);
+
+        let err = resolve_modules(&manifests, &requested(&["x"]), &HashSet::new(), false).unwrap_err();
+        assert!(matches!(err, ResolveError::ModuleNotFound { id, .. } if id == "x"));
+
+        let err = resolve_modules(&manifests, &requested(&["a"]), &HashSet::new(), false).unwrap_err();
+        assert!(matches!(err, ResolveError::DependencyNotFound { id, dependent, .. } if id == "b" && dependent == "a"));
+    }
+
+    #[test]
+    fn requested_version_wins_over_dependent_version() {
+        let mut versions = manifests("1.0", &[("a", &["b"], &[]), ("b", &[], &[])]);
+        versions.extend(manifests("2.0", &[("b", &[], &[])]));
+        let requested = vec![
+            ("a".to_string(), "1.0".to_string()),
+            ("b".to_string(), "2.0".to_string()),
+        ];
+        let modules = resolve_modules(&versions, &requested, &HashSet::new(), false).unwrap();
+
+        assert_eq!(resolved(&modules), ["b@2.0", "a@1.0"]);
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::manifest::v2::{Manifest, Module};
+
+
+#[derive(Clone, Debug)]
+pub enum SelectError {
+    NoMatch { selector: String, version: String },
+    Empty,
+}
+
+impl fmt::Display for SelectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SelectError::NoMatch { selector, version } => write!(
+                f, "Selector `{}` does not match any module in version `{}`.", selector, version,
+            ),
+            SelectError::Empty => write!(f, "No module left to bundle after exclusions."),
+        }
+    }
+}
+
+impl std::error::Error for SelectError {}
+
+
+/// A single entry of a module selection: an exact id, a `tag:<tag>`
+/// or a glob pattern. A leading `-` turns it into an exclusion.
+#[derive(Clone, Debug)]
+enum Selector {
+    Id(String),
+    Tag(String),
+    Glob(String),
+}
+
+impl Selector {
+    fn parse(selector: &str) -> Self {
+        if let Some(tag) = selector.strip_prefix("tag:") {
+            Selector::Tag(tag.to_string())
+        } else if selector.contains(['*', '?']) {
+            Selector::Glob(selector.to_string())
+        } else {
+            Selector::Id(selector.to_string())
+        }
+    }
+
+    fn matches(&self, module: &Module) -> bool {
+        match self {
+            Selector::Id(id) => module.id == *id,
+            Selector::Tag(tag) => module.tags.contains(tag),
+            Selector::Glob(pattern) => glob_match(pattern, &module.id),
+        }
+    }
+}
+
+
+/// The modules picked by a selection.
+#[derive(Clone, Debug, Default)]
+pub struct Selection {
+    /// Requested `(id, version)` pairs, in selection order. An id may appear at several versions.
+    pub requested: Vec<(String, String)>,
+    /// Ids that must be left out of the bundle.
+    pub excluded: HashSet<String>,
+}
+
+
+/// Expands the `(selector, version)` pairs into the requested modules along with
+/// the ids excluded from the bundle. Exclusions apply to every version and take
+/// precedence over any selector. An exact id is kept even when it does not exist,
+/// so that resolution reports it.
+pub fn select_modules(
+    manifests: &HashMap<String, Manifest>,
+    selection: &[(String, String)],
+) -> Result<Selection, SelectError> {
+    let mut excluded = HashSet::new();
+    for (entry, _) in selection {
+        if let Some(selector) = entry.strip_prefix('-') {
+            let selector = Selector::parse(selector);
+            for manifest in manifests.values() {
+                excluded.extend(manifest.modules.iter().filter(|m| selector.matches(m)).map(|m| m.id.clone()));
+            }
+            if let Selector::Id(id) = selector {
+                excluded.insert(id);
+            }
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let mut requested = vec![];
+    for (entry, version) in selection.iter().filter(|(entry, _)| !entry.starts_with('-')) {
+        let ids = match Selector::parse(entry) {
+            Selector::Id(id) => vec![id],
+            selector => {
+                let ids: Vec<String> = manifests
+                    .get(version)
+                    .map(|manifest| manifest.modules.iter().filter(|m| selector.matches(m)).map(|m| m.id.clone()).collect())
+                    .unwrap_or_default();
+                if ids.is_empty() {
+                    return Err(SelectError::NoMatch { selector: entry.to_string(), version: version.to_string() });
+                }
+                ids
+            },
+        };
+
+        for id in ids {
+            if !excluded.contains(&id) && seen.insert((id.clone(), version.to_string())) {
+                requested.push((id, version.to_string()));
+            }
+        }
+    }
+
+    if requested.is_empty() {
+        return Err(SelectError::Empty);
+    }
+    Ok(Selection { requested, excluded })
+}
+
+
+/// Matches `text` against a pattern where `*` stands for any sequence and `?` for any character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    t = matched + 1;
+                },
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::manifest::v2::fixtures;
+    use super::*;
+
+    /// Modules given as `(id, tags)`.
+    fn manifests(version: &str, modules: &[(&str, &[&str])]) -> HashMap<String, Manifest> {
+        fixtures::manifests(version, modules.iter().map(|(id, tags)| (*id, json!({ "tags": tags }))))
+    }
+
+    fn selection(entries: &[&str]) -> Vec<(String, String)> {
+        entries.iter().map(|entry| (entry.to_string(), "1.0".to_string())).collect()
+    }
+
+    fn ids(selection: &Selection) -> Vec<&str> {
+        selection.requested.iter().map(|(id, _)| id.as_str()).collect()
+    }
+
+    #[test]
+    fn glob_matches_literals_and_wildcards() {
+        assert!(glob_match("bs.block", "bs.block"));
+        assert!(!glob_match("bs.block", "bs.blocks"));
+        assert!(glob_match("bs.?ump", "bs.dump"));
+        assert!(!glob_match("bs.?ump", "bs.ump"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("bs.*", "bs."));
+        assert!(!glob_match("bs.*", "bs"));
+    }
+
+    #[test]
+    fn glob_star_backtracks() {
+        assert!(glob_match("*.load", "bs.load"));
+        assert!(glob_match("bs.*d", "bs.load.load"));
+        assert!(glob_match("*a*b", "xaxxab"));
+        assert!(glob_match("a*a*a", "aaaa"));
+        assert!(!glob_match("a*a*a", "aa"));
+        assert!(!glob_match("*ab", "abba"));
+        assert!(glob_match("**b", "aab"));
+    }
+
+    #[test]
+    fn selectors_are_expanded_in_order() {
+        let manifests = manifests("1.0", &[("bs.block", &["runtime"]), ("bs.dump", &["dev"]), ("bs.load", &[])]);
+        let selection = select_modules(&manifests, &selection(&["tag:dev", "bs.l*", "bs.dump"])).unwrap();
+
+        assert_eq!(ids(&selection), ["bs.dump", "bs.load"]);
+    }
+
+    #[test]
+    fn exclusions_take_precedence() {
+        let manifests = manifests("1.0", &[("bs.block", &["runtime"]), ("bs.dump", &["dev"]), ("bs.load", &[])]);
+        let selection = select_modules(&manifests, &selection(&["bs.*", "-tag:dev", "-bs.unknown"])).unwrap();
+
+        assert_eq!(ids(&selection), ["bs.block", "bs.load"]);
+        assert!(selection.excluded.contains("bs.dump"));
+        assert!(selection.excluded.contains("bs.unknown"));
+    }
+
+    #[test]
+    fn unknown_ids_are_kept_for_resolution() {
+        let manifests = manifests("1.0", &[("bs.block", &[])]);
+        let selection = select_modules(&manifests, &selection(&["bs.unknown"])).unwrap();
+
+        assert_eq!(ids(&selection), ["bs.unknown"]);
+    }
+
+    #[test]
+    fn unmatched_patterns_and_empty_selections_fail() {
+        let manifests = manifests("1.0", &[("bs.block", &[])]);
+
+        let err = select_modules(&manifests, &selection(&["tag:none"])).unwrap_err();
+        assert!(matches!(err, SelectError::NoMatch { selector, .. } if selector == "tag:none"));
+
+        let err = select_modules(&manifests, &selection(&["bs.block", "-bs.*"])).unwrap_err();
+        assert!(matches!(err, SelectError::Empty));
+    }
+}
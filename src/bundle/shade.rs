@@ -0,0 +1,183 @@
+use std::borrow::Cow;
+use std::fmt;
+
+
+#[derive(Clone, Debug)]
+pub enum ShadeError {
+    InvalidPrefix(String),
+    UnsupportedLayout,
+}
+
+impl fmt::Display for ShadeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShadeError::InvalidPrefix(prefix) => write!(
+                f, "Invalid shade prefix `{}`, only `a-z`, `0-9`, `_`, `-` and `.` are allowed.", prefix,
+            ),
+            ShadeError::UnsupportedLayout => write!(
+                f, "Shading is not supported by the separate layout, which keeps artifacts untouched.",
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ShadeError {}
+
+
+/// Moves the Bookshelf namespaces (`bs` and `bs.*`) under a private prefix, so that
+/// `bs.block:get` becomes `<prefix>.bs.block:get` in paths and resource locations.
+#[derive(Clone, Debug)]
+pub struct Shade {
+    prefix: String,
+}
+
+impl Shade {
+    pub fn new(prefix: &str) -> Result<Self, ShadeError> {
+        match !prefix.is_empty() && prefix.chars().all(is_namespace_char) {
+            true => Ok(Self { prefix: prefix.to_string() }),
+            false => Err(ShadeError::InvalidPrefix(prefix.to_string())),
+        }
+    }
+
+    /// Renames the namespace directory of `data/<namespace>/` and `assets/<namespace>/`
+    /// entries, including those nested in an overlay directory.
+    pub fn path<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        let components: Vec<&str> = path.split('/').collect();
+        let index = match components.as_slice() {
+            ["data" | "assets", namespace, _, ..] if is_shaded(namespace) => 1,
+            [_, "data" | "assets", namespace, _, ..] if is_shaded(namespace) => 2,
+            _ => return Cow::Borrowed(path),
+        };
+
+        let mut components: Vec<Cow<str>> = components.into_iter().map(Cow::Borrowed).collect();
+        components[index] = Cow::Owned(format!("{}.{}", self.prefix, components[index]));
+        Cow::Owned(components.join("/"))
+    }
+
+    /// Rewrites the resource locations of function and JSON files, other entries are kept as is.
+    pub fn contents<'a>(&self, path: &str, bytes: &'a [u8]) -> Cow<'a, [u8]> {
+        if !is_text(path) {
+            return Cow::Borrowed(bytes);
+        }
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Cow::Owned(self.text(text).into_bytes()),
+            Err(_) => Cow::Borrowed(bytes),
+        }
+    }
+
+    /// Prefixes every shaded namespace directly followed by `:` and a path, which covers
+    /// function calls, `#` tag references and resource locations inside strings.
+    fn text(&self, text: &str) -> String {
+        let mut shaded = String::with_capacity(text.len());
+        let mut rest = text;
+
+        while let Some(start) = rest.find(is_namespace_char) {
+            shaded.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = rest.find(|c| !is_namespace_char(c)).unwrap_or(rest.len());
+            let (token, tail) = rest.split_at(end);
+            let is_location = tail.strip_prefix(':').and_then(|path| path.chars().next()).is_some_and(is_path_char);
+            if is_shaded(token) && is_location {
+                shaded.push_str(&self.prefix);
+                shaded.push('.');
+            }
+            shaded.push_str(token);
+            rest = tail;
+        }
+
+        shaded.push_str(rest);
+        shaded
+    }
+}
+
+
+fn is_shaded(namespace: &str) -> bool {
+    namespace == "bs" || namespace.starts_with("bs.")
+}
+
+
+fn is_namespace_char(c: char) -> bool {
+    matches!(c, 'a'..='z' | '0'..='9' | '_' | '-' | '.')
+}
+
+
+fn is_path_char(c: char) -> bool {
+    is_namespace_char(c) || c == '/'
+}
+
+
+/// Whether the contents of an entry may hold resource locations.
+pub fn is_text(path: &str) -> bool {
+    path.ends_with(".mcfunction") || path.ends_with(".json")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shade() -> Shade {
+        Shade::new("my_pack").unwrap()
+    }
+
+    #[test]
+    fn prefix_must_be_a_namespace() {
+        assert!(Shade::new("my_pack.v1-2").is_ok());
+        assert!(matches!(Shade::new(""), Err(ShadeError::InvalidPrefix(_))));
+        assert!(matches!(Shade::new("My"), Err(ShadeError::InvalidPrefix(_))));
+        assert!(matches!(Shade::new("a:b"), Err(ShadeError::InvalidPrefix(_))));
+    }
+
+    #[test]
+    fn path_renames_shaded_namespaces() {
+        let shade = shade();
+        assert_eq!(shade.path("data/bs.block/function/get.mcfunction"), "data/my_pack.bs.block/function/get.mcfunction");
+        assert_eq!(shade.path("assets/bs/textures/a.png"), "assets/my_pack.bs/textures/a.png");
+        assert_eq!(shade.path("overlay_1/data/bs.load/tags/x.json"), "overlay_1/data/my_pack.bs.load/tags/x.json");
+    }
+
+    #[test]
+    fn path_keeps_other_entries() {
+        let shade = shade();
+        assert_eq!(shade.path("data/minecraft/tags/function/load.json"), "data/minecraft/tags/function/load.json");
+        assert_eq!(shade.path("data/bsx/function/a.mcfunction"), "data/bsx/function/a.mcfunction");
+        assert_eq!(shade.path("data/bs"), "data/bs");
+        assert_eq!(shade.path("a/b/data/bs/function/a.mcfunction"), "a/b/data/bs/function/a.mcfunction");
+        assert_eq!(shade.path("pack.mcmeta"), "pack.mcmeta");
+    }
+
+    #[test]
+    fn text_prefixes_resource_locations() {
+        let shade = shade();
+        assert_eq!(shade.text("function bs.block:get_block"), "function my_pack.bs.block:get_block");
+        assert_eq!(shade.text("function #bs.load:load"), "function #my_pack.bs.load:load");
+        assert_eq!(shade.text(r#"{"values":["bs:a/b"]}"#), r#"{"values":["my_pack.bs:a/b"]}"#);
+        assert_eq!(shade.text("$function bs.dump:var {var:$(v)}"), "$function my_pack.bs.dump:var {var:$(v)}");
+    }
+
+    #[test]
+    fn text_keeps_lookalikes() {
+        let shade = shade();
+        for text in [
+            "tellraw @a \"bs: done\"",
+            "abs:value",
+            "data.bs:x",
+            "bsx:get",
+            "function minecraft:bs",
+            r#"{"bs.block":1,"bs":2}"#,
+            "bs:",
+            "BS:get",
+        ] {
+            assert_eq!(shade.text(text), text);
+        }
+    }
+
+    #[test]
+    fn contents_only_rewrites_text_entries() {
+        let shade = shade();
+        let bytes = b"function bs:x";
+        assert_eq!(&*shade.contents("data/a/function/a.mcfunction", bytes), b"function my_pack.bs:x");
+        assert_eq!(&*shade.contents("data/a/structure/a.nbt", bytes), bytes);
+        assert_eq!(&*shade.contents("data/a/function/a.mcfunction", b"bs:x\xff"), b"bs:x\xff");
+    }
+}
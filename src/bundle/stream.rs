@@ -0,0 +1,66 @@
+use std::io::{self, Write};
+
+use bytes::{Bytes, BytesMut};
+use futures::stream::{self, BoxStream};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const CHANNEL_CAPACITY: usize = 8;
+
+
+pub type BundleStream = BoxStream<'static, io::Result<Bytes>>;
+
+
+/// Synchronous writer forwarding fixed-size chunks to an async receiver.
+/// At most `CHANNEL_CAPACITY` chunks are in flight, which bounds the memory
+/// used by a bundle no matter how large it is.
+pub struct ChannelWriter {
+    sender: Sender<io::Result<Bytes>>,
+    buffer: BytesMut,
+}
+
+impl ChannelWriter {
+    pub fn new() -> (Self, BundleStream) {
+        let (sender, receiver) = channel(CHANNEL_CAPACITY);
+        let writer = Self { sender, buffer: BytesMut::with_capacity(CHUNK_SIZE) };
+        (writer, into_stream(receiver))
+    }
+
+    /// Forwards an error to the receiver so that the response is aborted
+    /// instead of silently ending with a truncated archive.
+    pub fn fail(self, err: io::Error) {
+        let _ = self.sender.blocking_send(Err(err));
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let chunk = self.buffer.split().freeze();
+        self.sender
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Bundle receiver was dropped"))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+
+fn into_stream(receiver: Receiver<io::Result<Bytes>>) -> BundleStream {
+    Box::pin(stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    }))
+}
@@ -0,0 +1,358 @@
+use std::env;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context, Result};
+use axum::http::HeaderValue;
+use clap::Parser;
+use reqwest::Proxy;
+use serde::{Deserialize, Serialize};
+use toml::{Table, Value};
+
+use crate::api::rate_limit::RateLimit;
+use crate::bundle::archive::ArchiveLimits;
+use crate::bundle::limits::BundleLimits;
+use crate::upstream::health::BreakerConfig;
+use crate::upstream::UpstreamConfig;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Read when no configuration file is given and it exists in the working directory.
+const DEFAULT_CONFIG_PATH: &str = "bookshelf.toml";
+
+
+/// Every setting of the API, grouped in the sections of the configuration file.
+///
+/// Settings are read from, by increasing precedence: their default value, the TOML
+/// configuration file, `BS_<SECTION>_<KEY>` environment variables such as
+/// `BS_CACHE_MANIFEST_TTL` and command-line flags. Durations are given in seconds
+/// unless their key says otherwise, lists are comma-separated in the environment.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub cors: CorsConfig,
+    pub cache: CacheConfig,
+    pub sources: SourcesConfig,
+    pub upstream: UpstreamConfig,
+    pub circuit: BreakerConfig,
+    pub bundle: BundleLimits,
+    pub archive: ArchiveLimits,
+    pub rate_limit: RateLimit,
+}
+
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+    /// Largest archive accepted by `POST /inspect`, in bytes.
+    pub max_upload_size: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            max_upload_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins allowed to call the API, any origin is allowed when empty.
+    pub allow_list: Vec<String>,
+}
+
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Directory holding versions, manifests, module artifacts and bundles.
+    pub dir: String,
+    /// How long the list of versions is kept before being fetched again.
+    #[serde(with = "seconds")]
+    pub versions_ttl: Duration,
+    /// How long a manifest is kept in memory, manifests of released versions do not change.
+    #[serde(with = "seconds")]
+    pub manifest_ttl: Duration,
+    /// Minimum delay between two background refreshes of the same cached artifact.
+    #[serde(with = "seconds")]
+    pub refresh_cooldown: Duration,
+    /// Background refreshes running at the same time.
+    pub refresh_concurrency: usize,
+    #[serde(with = "seconds")]
+    pub refresh_timeout: Duration,
+    /// Total size of the cached bundles in bytes, the least recently used ones are evicted
+    /// beyond it. 0 disables the bundle cache.
+    pub bundles_max_size: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            dir: "cache".to_string(),
+            versions_ttl: Duration::from_secs(600),
+            manifest_ttl: Duration::from_secs(86400),
+            refresh_cooldown: Duration::from_secs(600),
+            refresh_concurrency: 3,
+            refresh_timeout: Duration::from_secs(5),
+            bundles_max_size: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Location of an entry of the on-disk cache.
+    pub fn path(&self, entry: &str) -> String {
+        format!("{}/{}", self.dir.trim_end_matches('/'), entry)
+    }
+}
+
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourcesConfig {
+    /// Repository whose releases provide module artifacts, as `<owner>/<name>`.
+    pub github_repository: String,
+    pub github_api: String,
+    pub modrinth_api: String,
+    /// Locations of the list of versions, tried in order.
+    pub versions_urls: Vec<String>,
+}
+
+impl Default for SourcesConfig {
+    fn default() -> Self {
+        Self {
+            github_repository: "mcbookshelf/Bookshelf".to_string(),
+            github_api: "https://api.github.com".to_string(),
+            modrinth_api: "https://api.modrinth.com/v3".to_string(),
+            versions_urls: vec![
+                "https://raw.githubusercontent.com/mcbookshelf/bookshelf/refs/heads/master/data/versions.json".to_string(),
+                "https://raw.githubusercontent.com/mcbookshelf/bookshelf/refs/heads/master/meta/versions.json".to_string(),
+            ],
+        }
+    }
+}
+
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// TOML configuration file, `bookshelf.toml` is read when it exists.
+    #[arg(short, long, env = "BS_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address to listen on, same as `--set server.bind=<BIND>`.
+    #[arg(long)]
+    bind: Option<String>,
+    /// Directory of the on-disk cache, same as `--set cache.dir=<CACHE_DIR>`.
+    #[arg(long)]
+    cache_dir: Option<String>,
+    /// Overrides any setting, such as `--set bundle.max_modules=50`.
+    #[arg(short, long = "set", value_name = "KEY=VALUE")]
+    set: Vec<String>,
+    /// Prints the resulting configuration and exits.
+    #[arg(long)]
+    print_config: bool,
+}
+
+
+impl Config {
+    /// Loads and validates the configuration, exiting on `--print-config`.
+    pub fn load() -> Result<Self> {
+        let cli = Cli::parse();
+        let defaults = Table::try_from(Config::default())?;
+
+        let mut table = Table::new();
+        if let Some(file) = read_file(cli.config.as_deref())? {
+            merge(&mut table, file);
+        }
+        merge(&mut table, env_layer(&defaults)?);
+        merge(&mut table, cli_layer(&cli, &defaults)?);
+
+        let config: Config = table.try_into().context("Invalid configuration")?;
+        config.validate().context("Invalid configuration")?;
+
+        if cli.print_config {
+            print!("{}", toml::to_string(&config)?);
+            std::process::exit(0);
+        }
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        for origin in &self.cors.allow_list {
+            ensure!(origin.parse::<HeaderValue>().is_ok(), "`cors.allow_list` contains an invalid origin `{}`", origin);
+        }
+        ensure!(!self.cache.dir.is_empty(), "`cache.dir` cannot be empty");
+        ensure!(self.cache.refresh_concurrency > 0, "`cache.refresh_concurrency` must be at least 1");
+        ensure!(
+            self.sources.github_repository.split('/').filter(|part| !part.is_empty()).count() == 2,
+            "`sources.github_repository` must be `<owner>/<name>`",
+        );
+        ensure!(!self.sources.versions_urls.is_empty(), "`sources.versions_urls` cannot be empty");
+        if !self.upstream.proxy.is_empty() {
+            Proxy::all(&self.upstream.proxy).context("`upstream.proxy` is not a valid URL")?;
+        }
+        ensure!(self.circuit.window > 0, "`circuit.window` must be at least 1");
+        ensure!((1..=100).contains(&self.circuit.error_rate), "`circuit.error_rate` must be between 1 and 100");
+        ensure!(self.bundle.max_modules > 0, "`bundle.max_modules` must be at least 1");
+        ensure!(self.bundle.max_versions > 0, "`bundle.max_versions` must be at least 1");
+        ensure!(self.bundle.max_concurrent_fetches > 0, "`bundle.max_concurrent_fetches` must be at least 1");
+        ensure!(
+            self.rate_limit.burst == 0 || self.rate_limit.per_minute > 0,
+            "`rate_limit.per_minute` must be at least 1 when rate limiting is enabled",
+        );
+        Ok(())
+    }
+}
+
+
+/// Makes the configuration available through `get`, only the first call has an effect.
+pub fn init(config: Config) {
+    let _ = CONFIG.set(config);
+}
+
+
+/// The configuration given to `init`, or the default one.
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+
+fn read_file(path: Option<&Path>) -> Result<Option<Table>> {
+    let path = match path {
+        Some(path) => path,
+        None if Path::new(DEFAULT_CONFIG_PATH).exists() => Path::new(DEFAULT_CONFIG_PATH),
+        None => return Ok(None),
+    };
+    let contents = std::fs::read_to_string(path).with_context(|| format!("Failed to read `{}`", path.display()))?;
+    let table = contents.parse().with_context(|| format!("Failed to parse `{}`", path.display()))?;
+    Ok(Some(table))
+}
+
+
+/// Settings given as `BS_<SECTION>_<KEY>` environment variables.
+fn env_layer(defaults: &Table) -> Result<Table> {
+    let mut layer = Table::new();
+    for (section, key, default) in settings(defaults) {
+        let name = format!("BS_{}_{}", section, key).to_uppercase();
+        if let Ok(raw) = env::var(&name) {
+            let value = parse_value(default, &raw).with_context(|| format!("Invalid value for `{}`", name))?;
+            set(&mut layer, section, key, value);
+        }
+    }
+    Ok(layer)
+}
+
+
+fn cli_layer(cli: &Cli, defaults: &Table) -> Result<Table> {
+    let mut overrides: Vec<(String, &str)> = vec![];
+    if let Some(bind) = &cli.bind {
+        overrides.push(("server.bind".to_string(), bind));
+    }
+    if let Some(dir) = &cli.cache_dir {
+        overrides.push(("cache.dir".to_string(), dir));
+    }
+    for assignment in &cli.set {
+        let Some((name, raw)) = assignment.split_once('=') else {
+            bail!("`--set {}` must be `<section>.<key>=<value>`", assignment);
+        };
+        overrides.push((name.trim().to_string(), raw));
+    }
+
+    let mut layer = Table::new();
+    for (name, raw) in overrides {
+        let Some((section, key, default)) = settings(defaults).find(|(s, k, _)| format!("{}.{}", s, k) == name) else {
+            bail!("Unknown setting `{}`", name);
+        };
+        let value = parse_value(default, raw).with_context(|| format!("Invalid value for `{}`", name))?;
+        set(&mut layer, section, key, value);
+    }
+    Ok(layer)
+}
+
+
+/// Every `(section, key, default)` of the configuration.
+fn settings(defaults: &Table) -> impl Iterator<Item = (&str, &str, &Value)> {
+    defaults.iter().flat_map(|(section, keys)| {
+        keys.as_table()
+            .into_iter()
+            .flatten()
+            .map(move |(key, default)| (section.as_str(), key.as_str(), default))
+    })
+}
+
+
+/// Parses a raw setting into the type of its default value.
+fn parse_value(default: &Value, raw: &str) -> Result<Value> {
+    Ok(match default {
+        Value::Integer(_) => Value::Integer(raw.trim().parse()?),
+        Value::Float(_) => Value::Float(raw.trim().parse()?),
+        Value::Boolean(_) => Value::Boolean(raw.trim().parse()?),
+        Value::Array(_) => Value::Array(raw
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| Value::String(item.to_string()))
+            .collect()
+        ),
+        _ => Value::String(raw.trim().to_string()),
+    })
+}
+
+
+fn set(layer: &mut Table, section: &str, key: &str, value: Value) {
+    let section = layer.entry(section).or_insert_with(|| Value::Table(Table::new()));
+    if let Value::Table(section) = section {
+        section.insert(key.to_string(), value);
+    }
+}
+
+
+/// Merges `layer` into `base`, keys of `layer` take precedence.
+fn merge(base: &mut Table, layer: Table) {
+    for (key, value) in layer {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(layer)) => merge(base, layer),
+            (_, value) => { base.insert(key, value); },
+        }
+    }
+}
+
+
+/// Durations written as a number of seconds.
+pub mod seconds {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_secs())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_secs(u64::deserialize(deserializer)?))
+    }
+}
+
+
+/// Durations written as a number of milliseconds.
+pub mod millis {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_millis(u64::deserialize(deserializer)?))
+    }
+}
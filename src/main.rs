@@ -1,17 +1,27 @@
-use std::env;
+use std::net::SocketAddr;
 
-use api::download::download;
+use api::download::{download, download_json, CONFLICTS_HEADER, FAILURES_HEADER, INCOMPATIBILITIES_HEADER};
+use api::health::health;
+use api::inspect::inspect;
 use api::manifest::manifest;
-use api::versions::versions;
-use axum::{http::{HeaderValue, Method}, routing::get, Router};
+use api::plan::plan;
+use api::rate_limit::rate_limit;
+use api::versions::{minecraft, versions};
+use axum::extract::DefaultBodyLimit;
+use axum::middleware;
+use axum::{http::{header, HeaderName, HeaderValue, Method}, routing::{get, post}, Router};
 use tower_http::compression::CompressionLayer;
 use tower_http::cors::{Any, CorsLayer};
 use utoipa::OpenApi;
 use utoipa_rapidoc::RapiDoc;
+use config::Config;
+use upstream::Upstream;
 
 mod api;
 mod bundle;
+mod config;
 mod manifest;
+mod upstream;
 mod utils;
 
 #[derive(OpenApi)]
@@ -22,12 +32,18 @@ mod utils;
     ),
     paths(
         crate::api::download::download,
+        crate::api::download::download_json,
+        crate::api::plan::plan,
+        crate::api::inspect::inspect,
         crate::api::versions::versions,
-        crate::api::manifest::manifest
+        crate::api::versions::minecraft,
+        crate::api::manifest::manifest,
+        crate::api::health::health
     ),
     tags(
         (name = "modules", description = "Download and manage modules."),
         (name = "versions", description = "Get available versions and their manifests."),
+        (name = "health", description = "Monitor the sources modules are downloaded from."),
     )
 )]
 pub struct ApiDoc;
@@ -59,31 +75,63 @@ const TEMPLATE: &str = r##"
 
 #[tokio::main]
 async fn main() {
+    let config = Config::load().unwrap_or_else(|err| {
+        eprintln!("{:#}", err);
+        std::process::exit(1);
+    });
+    config::init(config);
+    let config = config::get();
+
+    let upstream = Upstream::new(config.upstream.clone(), config.circuit.clone()).unwrap_or_else(|err| {
+        eprintln!("{:#}", err);
+        std::process::exit(1);
+    });
+
+    // Only the routes that fetch, build or unpack archives are rate limited.
+    let limited = Router::new()
+        .route("/download", get(download).post(download_json))
+        .route("/download/plan", get(plan))
+        .route("/inspect", post(inspect).layer(DefaultBodyLimit::max(config.server.max_upload_size)))
+        .route_layer(middleware::from_fn(rate_limit));
+
     let app = Router::new()
         .merge(RapiDoc::with_openapi("/openapi", ApiDoc::openapi()).custom_html(TEMPLATE).path("/"))
         .route("/versions", get(versions))
         .route("/version/{id}", get(manifest))
-        .route("/download", get(download))
-        .layer(create_cors_layer().await)
+        .route("/minecraft/{mc_version}", get(minecraft))
+        .route("/health", get(health))
+        .merge(limited)
+        .layer(create_cors_layer(&config.cors.allow_list))
+        .with_state(upstream)
         .layer(CompressionLayer::new());
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
+    let listener = tokio::net::TcpListener::bind(config.server.bind)
         .await
         .unwrap_or_else(|err| {
             eprintln!("Failed to bind listener: {}", err);
             std::process::exit(1);
         });
 
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
 }
 
-async fn create_cors_layer() -> CorsLayer {
-    match env::var("BS_CORS_ALLOW_LIST") {
-        Ok(origins) => CorsLayer::new().allow_origin(origins
-            .split(',')
-            .filter_map(|origin| origin.trim().parse().ok())
+fn create_cors_layer(allow_list: &[String]) -> CorsLayer {
+    match allow_list.is_empty() {
+        false => CorsLayer::new().allow_origin(allow_list
+            .iter()
+            .filter_map(|origin| origin.parse().ok())
             .collect::<Vec<HeaderValue>>()
         ),
-        Err(_) => CorsLayer::new().allow_origin(Any),
-    }.allow_methods([Method::GET])
+        true => CorsLayer::new().allow_origin(Any),
+    }
+    .allow_methods([Method::GET, Method::POST])
+    .allow_headers([header::CONTENT_TYPE, header::IF_NONE_MATCH, header::IF_MODIFIED_SINCE])
+    .expose_headers([
+        header::ETAG,
+        header::LAST_MODIFIED,
+        header::RETRY_AFTER,
+        HeaderName::from_static(CONFLICTS_HEADER),
+        HeaderName::from_static(INCOMPATIBILITIES_HEADER),
+        HeaderName::from_static(FAILURES_HEADER),
+    ])
 }
@@ -45,3 +45,33 @@ impl fmt::Display for ModuleKind {
         })
     }
 }
+
+
+#[cfg(test)]
+pub mod fixtures {
+    use std::collections::HashMap;
+
+    use serde_json::{json, Value};
+
+    use super::Manifest;
+
+    /// Manifests of a single `version`, each module is given by its id and the fields
+    /// it sets on top of the required ones.
+    pub fn manifests<'a>(version: &str, modules: impl IntoIterator<Item = (&'a str, Value)>) -> HashMap<String, Manifest> {
+        let modules: Vec<_> = modules.into_iter().map(|(id, fields)| {
+            let mut module = json!({
+                "id": id,
+                "name": id,
+                "slug": id,
+                "documentation": "",
+                "description": "",
+            });
+            if let (Some(module), Value::Object(fields)) = (module.as_object_mut(), fields) {
+                module.extend(fields);
+            }
+            module
+        }).collect();
+        let manifest = serde_json::from_value(json!({ "modules": modules })).unwrap();
+        HashMap::from([(version.to_string(), manifest)])
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::config::seconds;
+
+
+/// An upstream service module artifacts are downloaded from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    Modrinth,
+    Github,
+}
+
+impl Source {
+    const ALL: [Source; 2] = [Source::Modrinth, Source::Github];
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Modrinth => write!(f, "Modrinth"),
+            Source::Github => write!(f, "GitHub"),
+        }
+    }
+}
+
+
+/// When the circuit breaker of a source opens and for how long.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BreakerConfig {
+    /// Number of recent requests the error rate is computed over.
+    pub window: usize,
+    /// Requests needed in the window before the circuit can open.
+    pub min_requests: usize,
+    /// Share of failed requests, in percent, that opens the circuit.
+    pub error_rate: u32,
+    /// How long an open circuit skips the source before letting a probe through.
+    #[serde(with = "seconds")]
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            window: 20,
+            min_requests: 5,
+            error_rate: 50,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Requests are sent to the source.
+    Closed,
+    /// The source is skipped until the cool-down ends.
+    Open,
+    /// The cool-down ended, the next request decides whether the circuit closes again.
+    HalfOpen,
+}
+
+
+/// Health of a source as reported by `GET /health`.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct SourceStatus {
+    source: Source,
+    state: CircuitState,
+    /// Requests the error rate and latency are computed over.
+    recent_requests: usize,
+    /// Share of the recent requests that failed, between 0 and 1.
+    error_rate: f64,
+    /// Average time taken by the recent requests, retries included.
+    average_latency_ms: u64,
+    /// Time left before an open circuit lets a probe through.
+    retry_in_seconds: Option<u64>,
+    last_error: Option<String>,
+}
+
+
+/// Returned instead of sending a request to a source whose circuit is open.
+#[derive(Clone, Debug)]
+pub struct SourceUnavailable(pub Source);
+
+impl fmt::Display for SourceUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is skipped after repeated failures, see `GET /health`.", self.0)
+    }
+}
+
+impl std::error::Error for SourceUnavailable {}
+
+
+/// Handed to the request probing a half-open circuit, only its outcome closes or reopens it.
+#[derive(Debug)]
+pub struct Probe(Instant);
+
+
+struct Outcome {
+    success: bool,
+    latency: Duration,
+}
+
+
+#[derive(Default)]
+struct SourceState {
+    outcomes: VecDeque<Outcome>,
+    opened: Option<Instant>,
+    /// Start of the request probing a half-open circuit, a stale probe lets another one through.
+    probe: Option<Instant>,
+    last_error: Option<String>,
+}
+
+impl SourceState {
+    fn error_rate(&self) -> f64 {
+        let failures = self.outcomes.iter().filter(|outcome| !outcome.success).count();
+        failures as f64 / self.outcomes.len().max(1) as f64
+    }
+
+    fn state(&self, config: &BreakerConfig, now: Instant) -> CircuitState {
+        match self.opened {
+            None => CircuitState::Closed,
+            Some(opened) if now.duration_since(opened) < config.cooldown => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+}
+
+
+/// Tracks recent requests to every source and opens a circuit breaker
+/// for a source once too many of them failed.
+pub struct Health {
+    config: BreakerConfig,
+    sources: DashMap<Source, SourceState>,
+}
+
+impl Health {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self { config, sources: DashMap::new() }
+    }
+
+    /// Checks that a request can be sent to the source. A half-open circuit
+    /// lets a single probe through, others keep skipping the source.
+    pub fn acquire(&self, source: Source) -> Result<Option<Probe>, SourceUnavailable> {
+        let now = Instant::now();
+        let mut state = self.sources.entry(source).or_default();
+        match state.state(&self.config, now) {
+            CircuitState::Closed => Ok(None),
+            CircuitState::Open => Err(SourceUnavailable(source)),
+            CircuitState::HalfOpen => {
+                if state.probe.is_some_and(|probe| now.duration_since(probe) < self.config.cooldown) {
+                    return Err(SourceUnavailable(source));
+                }
+                state.probe = Some(now);
+                Ok(Some(Probe(now)))
+            },
+        }
+    }
+
+    /// Records the outcome of a request, `error` is set for failures only.
+    pub fn record(&self, source: Source, probe: Option<Probe>, latency: Duration, error: Option<String>) {
+        let now = Instant::now();
+        let mut state = self.sources.entry(source).or_default();
+        let success = error.is_none();
+
+        // The current probe of a half-open circuit decides for the whole source, other
+        // requests, and probes that went stale, only count towards the error rate.
+        if let Some(Probe(started)) = probe
+            && state.probe == Some(started)
+        {
+            state.probe = None;
+            state.opened = if success { None } else { Some(now) };
+            state.outcomes.clear();
+        }
+
+        state.outcomes.push_back(Outcome { success, latency });
+        while state.outcomes.len() > self.config.window {
+            state.outcomes.pop_front();
+        }
+
+        if let Some(error) = error {
+            eprintln!("{} request failed: {}", source, error);
+            state.last_error = Some(error);
+            let tripped = state.outcomes.len() >= self.config.min_requests
+                && state.error_rate() * 100.0 >= self.config.error_rate as f64;
+            if state.opened.is_none() && tripped {
+                eprintln!("{} is skipped for {} seconds", source, self.config.cooldown.as_secs());
+                state.opened = Some(now);
+            }
+        }
+    }
+
+    pub fn statuses(&self) -> Vec<SourceStatus> {
+        let now = Instant::now();
+        Source::ALL.iter().map(|&source| {
+            let state = self.sources.entry(source).or_default();
+            let total: Duration = state.outcomes.iter().map(|outcome| outcome.latency).sum();
+            let circuit = state.state(&self.config, now);
+            SourceStatus {
+                source,
+                state: circuit,
+                recent_requests: state.outcomes.len(),
+                error_rate: state.error_rate(),
+                average_latency_ms: (total / state.outcomes.len().max(1) as u32).as_millis() as u64,
+                retry_in_seconds: state.opened
+                    .filter(|_| circuit == CircuitState::Open)
+                    .map(|opened| (self.config.cooldown - now.duration_since(opened)).as_secs()),
+                last_error: state.last_error.clone(),
+            }
+        }).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_millis(50);
+    const LATENCY: Duration = Duration::from_millis(10);
+
+    fn health(cooldown: Duration) -> Health {
+        Health::new(BreakerConfig { window: 4, min_requests: 2, error_rate: 50, cooldown })
+    }
+
+    fn status(health: &Health) -> SourceStatus {
+        health.statuses().into_iter().find(|status| status.source == Source::Modrinth).unwrap()
+    }
+
+    fn state(health: &Health) -> CircuitState {
+        status(health).state
+    }
+
+    fn fail(health: &Health, probe: Option<Probe>) {
+        health.record(Source::Modrinth, probe, LATENCY, Some("error".to_string()));
+    }
+
+    fn succeed(health: &Health, probe: Option<Probe>) {
+        health.record(Source::Modrinth, probe, LATENCY, None);
+    }
+
+    /// Opens the circuit and waits for the cool-down to end.
+    fn half_open() -> Health {
+        let health = health(COOLDOWN);
+        fail(&health, None);
+        fail(&health, None);
+        sleep(COOLDOWN);
+        assert_eq!(state(&health), CircuitState::HalfOpen);
+        health
+    }
+
+    #[test]
+    fn opens_once_the_error_rate_is_reached() {
+        let health = health(Duration::from_secs(3600));
+        succeed(&health, None);
+        assert!(matches!(health.acquire(Source::Modrinth), Ok(None)));
+
+        fail(&health, None);
+        assert_eq!(state(&health), CircuitState::Open);
+        assert!(health.acquire(Source::Modrinth).is_err());
+        assert!(matches!(health.acquire(Source::Github), Ok(None)));
+    }
+
+    #[test]
+    fn needs_enough_requests_to_open() {
+        let health = health(Duration::from_secs(3600));
+        fail(&health, None);
+        assert_eq!(state(&health), CircuitState::Closed);
+        assert!(health.acquire(Source::Modrinth).is_ok());
+    }
+
+    #[test]
+    fn half_open_lets_a_single_probe_through() {
+        let health = half_open();
+        assert!(matches!(health.acquire(Source::Modrinth), Ok(Some(_))));
+        assert!(health.acquire(Source::Modrinth).is_err());
+    }
+
+    #[test]
+    fn successful_probe_closes() {
+        let health = half_open();
+        let probe = health.acquire(Source::Modrinth).unwrap();
+        succeed(&health, probe);
+
+        assert_eq!(state(&health), CircuitState::Closed);
+        assert_eq!(status(&health).recent_requests, 1);
+        assert!(matches!(health.acquire(Source::Modrinth), Ok(None)));
+    }
+
+    #[test]
+    fn failed_probe_reopens() {
+        let health = half_open();
+        let probe = health.acquire(Source::Modrinth).unwrap();
+        fail(&health, probe);
+
+        assert_eq!(state(&health), CircuitState::Open);
+        assert!(health.acquire(Source::Modrinth).is_err());
+    }
+
+    #[test]
+    fn other_requests_do_not_decide() {
+        let health = half_open();
+        let probe = health.acquire(Source::Modrinth).unwrap();
+        succeed(&health, None);
+
+        assert_eq!(state(&health), CircuitState::HalfOpen);
+        assert!(health.acquire(Source::Modrinth).is_err());
+
+        fail(&health, probe);
+        assert_eq!(state(&health), CircuitState::Open);
+    }
+
+    #[test]
+    fn stale_probe_is_replaced() {
+        let health = half_open();
+        let stale = health.acquire(Source::Modrinth).unwrap();
+        sleep(COOLDOWN);
+        let probe = health.acquire(Source::Modrinth).unwrap();
+        assert!(probe.is_some());
+
+        succeed(&health, stale);
+        assert_eq!(state(&health), CircuitState::HalfOpen);
+
+        succeed(&health, probe);
+        assert_eq!(state(&health), CircuitState::Closed);
+    }
+}
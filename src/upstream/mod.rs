@@ -0,0 +1,152 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Client, IntoUrl, Proxy, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+use crate::config::{millis, seconds};
+use health::{BreakerConfig, Health, SourceStatus};
+
+pub use health::Source;
+
+pub mod health;
+
+const USER_AGENT: &str = concat!("Bookshelf-API/", env!("CARGO_PKG_VERSION"));
+
+
+/// Settings of the client used to reach Modrinth and GitHub.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    #[serde(with = "seconds")]
+    pub connect_timeout: Duration,
+    /// Longest wait for the next chunk of a response.
+    #[serde(with = "seconds")]
+    pub read_timeout: Duration,
+    /// Attempts made after the first one when upstream is unavailable or rate limiting.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every following one.
+    #[serde(rename = "retry_delay_ms", with = "millis")]
+    pub retry_delay: Duration,
+    /// Longest delay waited between attempts, a longer `Retry-After` fails the request.
+    #[serde(with = "seconds")]
+    pub max_retry_delay: Duration,
+    /// Proxy for every upstream request, the `HTTP_PROXY` and `HTTPS_PROXY` variables apply when empty.
+    pub proxy: String,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(30),
+            retries: 3,
+            retry_delay: Duration::from_millis(500),
+            max_retry_delay: Duration::from_secs(30),
+            proxy: String::new(),
+        }
+    }
+}
+
+
+/// The client shared by every upstream request. Transient failures, `5xx` and
+/// `429` responses are retried with an exponential backoff, honoring `Retry-After`.
+#[derive(Clone)]
+pub struct Upstream {
+    client: Client,
+    config: UpstreamConfig,
+    health: Arc<Health>,
+}
+
+impl Upstream {
+    pub fn new(config: UpstreamConfig, breaker: BreakerConfig) -> Result<Self> {
+        let mut builder = Client::builder()
+            .user_agent(USER_AGENT)
+            .connect_timeout(config.connect_timeout)
+            .read_timeout(config.read_timeout);
+        if !config.proxy.is_empty() {
+            let proxy = Proxy::all(&config.proxy).with_context(|| format!("Invalid upstream proxy `{}`", config.proxy))?;
+            builder = builder.proxy(proxy);
+        }
+
+        let client = builder.build().context("Failed to create the upstream client")?;
+        let health = Arc::new(Health::new(breaker));
+        Ok(Self { client, config, health })
+    }
+
+    /// Downloads a body from a module source, which is skipped while its circuit breaker is open.
+    /// The outcome is recorded once the body is read, so that interrupted downloads count as failures.
+    /// Client errors other than `429` mean the source answered, they do not count as failures.
+    pub async fn get_bytes_from(&self, source: Source, url: impl IntoUrl) -> Result<Bytes> {
+        let probe = self.health.acquire(source)?;
+        let start = Instant::now();
+        let result = match self.get(url).await {
+            Ok(response) => response.bytes().await.map_err(anyhow::Error::from),
+            Err(err) => Err(err),
+        };
+
+        let error = result.as_ref().err().filter(|err| !is_client_error(err)).map(|err| format!("{:#}", err));
+        self.health.record(source, probe, start.elapsed(), error);
+        result
+    }
+
+    pub fn health(&self) -> Vec<SourceStatus> {
+        self.health.statuses()
+    }
+
+    /// Sends a `GET` request and fails on any error status once retries are exhausted.
+    pub async fn get(&self, url: impl IntoUrl) -> Result<Response> {
+        let url = url.into_url()?;
+        let mut attempt = 0;
+
+        loop {
+            let result = self.client.get(url.clone()).send().await;
+            let delay = match &result {
+                Ok(response) if is_transient(response.status()) => {
+                    retry_after(response.headers()).unwrap_or_else(|| self.backoff(attempt))
+                },
+                Err(err) if err.is_connect() || err.is_timeout() => self.backoff(attempt),
+                _ => return Ok(result?.error_for_status()?),
+            };
+
+            if attempt >= self.config.retries || delay > self.config.max_retry_delay {
+                return Ok(result?.error_for_status()?);
+            }
+            attempt += 1;
+            sleep(delay).await;
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.config.retry_delay.saturating_mul(2u32.saturating_pow(attempt)).min(self.config.max_retry_delay)
+    }
+}
+
+
+fn is_transient(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+
+fn is_client_error(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<reqwest::Error>()
+        .and_then(reqwest::Error::status)
+        .is_some_and(|status| status.is_client_error() && !is_transient(status))
+}
+
+
+/// Reads `Retry-After`, given either as a number of seconds or as a date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value).ok()?;
+            Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+        },
+    }
+}
@@ -1,9 +1,16 @@
+use std::fmt::LowerHex;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
 
 use anyhow::{Context, Result};
-use tokio::fs::{create_dir_all, File, OpenOptions};
+use sha2::digest::Output;
+use sha2::{Digest, Sha256};
+use tokio::fs::{create_dir_all, remove_file, rename, File};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
 
 pub async fn read_from_file(path: &str) -> Result<Vec<u8>> {
     let mut file = File::open(path).await.context("Failed to open file")?;
@@ -12,6 +19,10 @@ pub async fn read_from_file(path: &str) -> Result<Vec<u8>> {
     Ok(buffer)
 }
 
+pub async fn modified(path: &str) -> Option<SystemTime> {
+    tokio::fs::metadata(path).await.ok()?.modified().ok()
+}
+
 pub async fn read_from_json_file<T>(path: &str) -> Result<T>
 where
     T: serde::de::DeserializeOwned,
@@ -20,20 +31,24 @@ where
     serde_json::from_slice(&buffer).context("Failed to deserialize JSON")
 }
 
+/// Writes to a temporary file renamed over the path, readers never see a partial file.
 pub async fn write_to_file(path: &str, bytes: &[u8]) -> Result<()> {
     if let Some(dir) = Path::new(path).parent() {
         create_dir_all(dir).await.context("Failed to create parent directory")?;
     }
 
-    let mut file = OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .create(true)
-        .open(path)
-        .await
-        .context("Failed to open file for writing")?;
+    let temp_path = format!("{}.{}.tmp", path, TEMP_COUNTER.fetch_add(1, Ordering::Relaxed));
+    let mut file = File::create(&temp_path).await.context("Failed to open file for writing")?;
+    let written = async {
+        file.write_all(bytes).await?;
+        file.flush().await
+    }.await;
 
-    file.write_all(bytes).await.context("Failed to write data to file")
+    if let Err(err) = written {
+        let _ = remove_file(&temp_path).await;
+        return Err(err).context("Failed to write data to file");
+    }
+    rename(&temp_path, path).await.context("Failed to move file into place")
 }
 
 pub async fn write_to_json_file<T>(path: &str, data: &T) -> Result<()>
@@ -43,3 +58,29 @@ where
     let data = serde_json::to_string(data).context("Failed to serialize data")?;
     write_to_file(path, data.as_bytes()).await
 }
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+pub async fn sha256_file(path: &str) -> Result<String> {
+    digest_file::<Sha256>(path).await
+}
+
+/// Hashes a file in chunks, without holding it in memory.
+pub async fn digest_file<D: Digest>(path: &str) -> Result<String>
+where
+    Output<D>: LowerHex,
+{
+    let mut file = File::open(path).await.context("Failed to open file")?;
+    let mut hasher = D::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await.context("Failed to read file")?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}